@@ -36,6 +36,6 @@ pub async fn get_user_by_id(id: Principal) -> Result<UserProfile, String> {
 #[ic_cdk::query]
 pub async fn get_user_list() -> Result<Vec<UserProfile>, String> {
     STATE.with(|state| {
-        Ok(state.borrow().user_data.values().map(|v| v.clone()).collect::<Vec<_>>())
+        Ok(state.borrow().user_data.values().collect::<Vec<_>>())
     })
 }
\ No newline at end of file
@@ -17,6 +17,7 @@ pub fn create_user(user_profile: UserProfile) -> Result<String, String> {
                 description: user_profile.description,
                 achievements: user_profile.achievements,
                 stats: user_profile.stats,
+                created_at: Some(ic_cdk::api::time()),
             };
             state.user_data.insert(caller, new_user);
             Ok("User created!".to_string())
@@ -29,16 +30,17 @@ pub fn create_user(user_profile: UserProfile) -> Result<String, String> {
 pub fn update_user(user_profile: UserProfile) -> Result<String, String> {
     let caller = ic_cdk::caller();
     STATE.with(|state| {
-        let updated_user = UserProfile {
-            id: caller,
-            name: user_profile.name,
-            skill: user_profile.skill,
-            description: user_profile.description,
-            achievements: user_profile.achievements,
-            stats: user_profile.stats,
-        };
         let mut state = state.borrow_mut();
-        if state.user_data.contains_key(&caller) {
+        if let Some(existing_user) = state.user_data.get(&caller) {
+            let updated_user = UserProfile {
+                id: caller,
+                name: user_profile.name,
+                skill: user_profile.skill,
+                description: user_profile.description,
+                achievements: user_profile.achievements,
+                stats: user_profile.stats,
+                created_at: existing_user.created_at,
+            };
             state.user_data.insert(caller, updated_user);
             Ok("User updated!".to_string())
         } else {
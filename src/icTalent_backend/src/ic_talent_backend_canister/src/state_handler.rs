@@ -68,7 +68,7 @@ pub fn get_token_data_memory() -> Memory {
 
 // Implement Storable for UserProfile
 impl Storable for UserProfile {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
@@ -83,7 +83,7 @@ impl Storable for UserProfile {
 // Implement Storable for TokenMetadata
 
 impl Storable for TokenMetadata {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
@@ -9,6 +9,8 @@ pub struct UserProfile {
     pub description: String,
     pub achievements: Vec<String>,
     pub stats: UserStats,
+    // Set by create_user; profiles created before this was tracked have none
+    pub created_at: Option<u64>,
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone)]
@@ -0,0 +1,104 @@
+use candid::Principal;
+use crate::state_handler::{ensure_role, State, STATE};
+use crate::types::*;
+use ic_cdk::api::caller;
+
+// Changes to who is the admin are logged too, as the admin is implicitly a SuperAdmin
+pub fn record_change(state: &mut State, actor: Principal, principal: Principal, role: Role, change: RoleChange) {
+    let id = state.role_audit_log.last_key_value().map(|(id, _)| id + 1).unwrap_or(0);
+    state.role_audit_log.insert(id, RoleAuditEntry {
+        id,
+        actor,
+        principal,
+        role,
+        change,
+        at: ic_cdk::api::time(),
+    });
+}
+
+#[ic_cdk::update]
+pub fn grant_role(principal: Principal, role: Role) -> Result<String, FactoryError> {
+    let actor = caller();
+    ensure_role(actor, Role::SuperAdmin)?;
+
+    if principal == Principal::anonymous() {
+        return Err(FactoryError::InvalidArgument("Roles cannot be granted to the anonymous principal".to_string()));
+    }
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let mut roles = state.roles.get(&principal).unwrap_or_default();
+        if roles.0.contains(&role) {
+            return Err(FactoryError::InvalidState(format!("{} already has the {:?} role", principal, role)));
+        }
+        roles.0.push(role);
+        roles.0.sort();
+        state.roles.insert(principal, roles);
+        record_change(&mut state, actor, principal, role, RoleChange::Granted);
+        Ok(format!("Granted {:?} to {}", role, principal))
+    })
+}
+
+#[ic_cdk::update]
+pub fn revoke_role(principal: Principal, role: Role) -> Result<String, FactoryError> {
+    let actor = caller();
+    ensure_role(actor, Role::SuperAdmin)?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        // The configured admin keeps SuperAdmin until admin itself is handed over
+        if principal == state.admin() && role == Role::SuperAdmin {
+            return Err(FactoryError::InvalidState("The SuperAdmin role of the admin cannot be revoked".to_string()));
+        }
+
+        let mut roles = state.roles.get(&principal).unwrap_or_default();
+        if !roles.0.contains(&role) {
+            return Err(FactoryError::InvalidState(format!("{} does not have the {:?} role", principal, role)));
+        }
+        roles.0.retain(|granted| *granted != role);
+        if roles.0.is_empty() {
+            state.roles.remove(&principal);
+        } else {
+            state.roles.insert(principal, roles);
+        }
+        record_change(&mut state, actor, principal, role, RoleChange::Revoked);
+        Ok(format!("Revoked {:?} from {}", role, principal))
+    })
+}
+
+#[ic_cdk::query]
+pub fn get_roles() -> Result<Vec<(Principal, Vec<Role>)>, FactoryError> {
+    ensure_role(caller(), Role::SuperAdmin)?;
+
+    STATE.with(|state| {
+        let state = state.borrow();
+        let mut roles: Vec<_> = state.roles.iter().map(|(principal, roles)| (principal, roles.0)).collect();
+        if !roles.iter().any(|(principal, _)| *principal == state.admin()) {
+            roles.push((state.admin(), vec![Role::SuperAdmin]));
+        }
+        Ok(roles)
+    })
+}
+
+#[ic_cdk::query]
+pub fn get_my_roles() -> Result<Vec<Role>, FactoryError> {
+    let caller = caller();
+
+    STATE.with(|state| {
+        let state = state.borrow();
+        let mut roles = state.roles.get(&caller).unwrap_or_default().0;
+        if caller == state.admin() && !roles.contains(&Role::SuperAdmin) {
+            roles.insert(0, Role::SuperAdmin);
+        }
+        Ok(roles)
+    })
+}
+
+#[ic_cdk::query]
+pub fn get_role_audit_log() -> Result<Vec<RoleAuditEntry>, FactoryError> {
+    ensure_role(caller(), Role::SuperAdmin)?;
+
+    STATE.with(|state| {
+        Ok(state.borrow().role_audit_log.values().collect())
+    })
+}
@@ -3,42 +3,43 @@ use crate::types::*;
 use candid::Principal;
 
 #[ic_cdk::query]
-pub async fn get_token_metadata(token_id: Principal) -> Result<TokenMetadata, String> {
+pub async fn get_token_metadata(token_id: Principal) -> Result<TokenMetadata, FactoryError> {
     STATE.with(|state| {
         state.borrow().tokens.get(&token_id)
-            .ok_or("Token not found".to_string())
-            .map(|metadata| metadata.clone())
+            .ok_or(FactoryError::TokenNotFound(token_id))
     })
 }
 #[ic_cdk::query]
-pub async fn get_faucet_requests() -> Result<Vec<( Principal, FaucetTokenRequest)>, String> {
-    let caller = ic_cdk::caller();
-    if caller != STATE.with(|state| state.borrow().admin) {
-        return Err(format!("Not authorized, caller: {}", caller.to_text()).to_string());
-    }
+pub async fn get_list_of_tokens() -> Result<Vec<(Principal, TokenMetadata)>, FactoryError> {
     STATE.with(|state| {
-        let faucet_requests = state.borrow().faucet_requests.iter().map(|(request_sender, request)| (request_sender, request)  ).collect()    ;
-        Ok(faucet_requests)
+        let tokens = state.borrow().tokens.iter().map(|(token_id, metadata)| (token_id, metadata.clone())).collect();
+        Ok(tokens)
     })
 }
 
 #[ic_cdk::query]
-pub async fn get_list_of_tokens() -> Result<Vec<(Principal, TokenMetadata)>, String> {
+pub async fn get_admin() -> Result<Principal, FactoryError> {
     STATE.with(|state| {
-        let tokens = state.borrow().tokens.iter().map(|(token_id, metadata)| (token_id, metadata.clone())).collect();
-        Ok(tokens)
+        Ok(state.borrow().admin())
+    })
+}
+
+#[ic_cdk::query]
+pub async fn get_pending_admin() -> Result<Option<PendingAdminTransfer>, FactoryError> {
+    STATE.with(|state| {
+        Ok(state.borrow().config.get().pending_admin.clone())
     })
 }
 
 #[ic_cdk::query]
-pub async fn get_admin() -> Result<Principal, String> {
+pub async fn get_backend_canister() -> Result<Option<Principal>, FactoryError> {
     STATE.with(|state| {
-        Ok(state.borrow().admin)
+        Ok(state.borrow().config.get().backend_canister_id)
     })
 }
 
 #[ic_cdk::query]
-pub async fn get_user_token_metadata() -> Result<(Principal, TokenMetadata), String> {
+pub async fn get_user_token_metadata() -> Result<(Principal, TokenMetadata), FactoryError> {
     let user = ic_cdk::caller();
     STATE.with(|state| {
         let state = state.borrow();
@@ -48,10 +49,10 @@ pub async fn get_user_token_metadata() -> Result<(Principal, TokenMetadata), Str
                 // Then get the token metadata
                 match state.tokens.get(&token_id) {
                     Some(metadata) => Ok((token_id, metadata.clone())),
-                    None => Err("Token metadata not found for existing token mapping".to_string())
+                    None => Err(FactoryError::TokenNotFound(token_id))
                 }
             },
-            None => Err("User hasn't created a token yet".to_string())
+            None => Err(FactoryError::InvalidState("User hasn't created a token yet".to_string()))
         }
     })
 }
@@ -1,194 +1,117 @@
-use candid::{Nat,Principal};
+use candid::Principal;
+use crate::access_control;
 use crate::state_handler::STATE;
 use crate::types::*;
-use icrc_ledger_types::icrc1::transfer::*;
-use icrc_ledger_types::icrc1::account::Account;
-use icrc_ledger_types::icrc2::transfer_from::*;
 
+// Admin handover is two-step so a mistyped principal never becomes admin:
+// the current admin proposes, the candidate accepts
 #[ic_cdk::update]
-
-pub fn send_token_faucet_request(number_of_tokens: u32) -> Result<String, String> {
+pub fn propose_admin(candidate: Principal, expires_in_seconds: Option<u64>) -> Result<String, FactoryError> {
     let caller = ic_cdk::caller();
 
-    if caller == Principal::anonymous() {
-        return Err("You are not allowed to request tokens".to_string());
-    };
-
-    STATE.with(|state| {
-        
-        let faucet_request = state.borrow().faucet_requests.get(&caller).unwrap_or(FaucetTokenRequest {
-            current_token_request: number_of_tokens,
-            total_number_of_request: 0,
-            total_token_given: 0,
-            status: "pending".to_string(),
-        });
-
-        let new_faucet_request = FaucetTokenRequest {
-            current_token_request: number_of_tokens,
-            total_number_of_request: faucet_request.total_number_of_request + 1,
-            total_token_given: faucet_request.total_token_given,
-            status: "pending".to_string(),
-        };
-
-        state.borrow_mut().faucet_requests.insert(caller, new_faucet_request);
-    });
-
-    Ok(format!("Faucet request sent for {} tokens", number_of_tokens))
-}
-
+    if candidate == Principal::anonymous() {
+        return Err(FactoryError::InvalidArgument("The admin cannot be the anonymous principal".to_string()));
+    }
 
-#[ic_cdk::update]
-pub fn register_admin() -> Result<String, String> {
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        
-        // Check if admin is already registered
-        if state.is_admin_registered {
-            return Err("Admin already registered".to_string());
+        if caller != state.admin() {
+            return Err(FactoryError::NotAuthorized { caller });
         }
-        
-        // Set the caller as admin
-        state.admin = ic_cdk::caller();
-        state.is_admin_registered = true;
-        
-        Ok("Admin registered".to_string())
+
+        let now = ic_cdk::api::time();
+        let expires_at = expires_in_seconds.map(|seconds| now.saturating_add(seconds.saturating_mul(1_000_000_000)));
+        // A new proposal replaces any earlier one
+        state.update_config(|config| {
+            config.pending_admin = Some(PendingAdminTransfer {
+                candidate,
+                proposed_by: caller,
+                proposed_at: now,
+                expires_at,
+            });
+        });
+
+        Ok(format!("Admin transfer to {} proposed", candidate))
     })
 }
 
 #[ic_cdk::update]
-pub fn change_admin(new_admin: Principal) -> Result<String, String> {
+pub fn accept_admin() -> Result<String, FactoryError> {
     let caller = ic_cdk::caller();
-    
+
     STATE.with(|state| {
-        let state = state.borrow();
-        
-        // Check if admin is registered
-        if !state.is_admin_registered {
-            return Err("Admin not registered".to_string());
+        let mut state = state.borrow_mut();
+        let pending = state.config.get().pending_admin.clone()
+            .ok_or(FactoryError::NoPendingAdminTransfer)?;
+        if caller != pending.candidate {
+            return Err(FactoryError::NotAuthorized { caller });
         }
-        
-        // Check if caller is current admin
-        if caller != state.admin {
-            return Err(format!("Not authorized, caller: {}", caller.to_text()).to_string());
+        if let Some(expires_at) = pending.expires_at {
+            if ic_cdk::api::time() > expires_at {
+                return Err(FactoryError::AdminTransferExpired { expired_at: expires_at });
+            }
         }
-        
-        Ok(())
-    })?;
 
-    STATE.with(|state| {
-        state.borrow_mut().admin = new_admin;
-    });
+        let old_admin = state.admin();
+        state.update_config(|config| {
+            config.admin = caller;
+            config.pending_admin = None;
+        });
+        access_control::record_change(&mut state, pending.proposed_by, old_admin, Role::SuperAdmin, RoleChange::Revoked);
+        access_control::record_change(&mut state, caller, caller, Role::SuperAdmin, RoleChange::Granted);
 
-    Ok("Admin changed successfully".to_string())
+        Ok("Admin transfer accepted".to_string())
+    })
 }
 
 #[ic_cdk::update]
-    pub async fn accept_token_request(user: Principal) -> Result<String, Error> {
+pub fn cancel_admin_transfer() -> Result<String, FactoryError> {
     let caller = ic_cdk::caller();
-    
+
     STATE.with(|state| {
-        let state = state.borrow();
-        if caller != state.admin {
-            return Err(Error::NotAuthorized);
+        let mut state = state.borrow_mut();
+        if caller != state.admin() {
+            return Err(FactoryError::NotAuthorized { caller });
         }
-        
-        if !state.faucet_requests.contains_key(&user) {
-            return Err(Error::RequestNotFound);
+        if state.config.get().pending_admin.is_none() {
+            return Err(FactoryError::NoPendingAdminTransfer);
         }
-        Ok(())
-    })?;
 
-    // Get request details and update stats
-    let request = STATE.with(|state| {
-        let mut state = state.borrow_mut();
-        let mut request = state.faucet_requests.get(&user).unwrap();
-        request.total_number_of_request += 1u32;
-        request.total_token_given += request.current_token_request as u64;
-        request.status = "approved".to_string();
-        state.faucet_requests.insert(user, request.clone());
-        request
-    });
-
-    // Trigger token transfer
-    transfer_tokens(user, request.current_token_request).await
-        .map_err(|e| Error::TransferFailed(e.to_string()))?;
-
-    Ok("Request accepted".to_string())
+        state.update_config(|config| config.pending_admin = None);
+        Ok("Admin transfer cancelled".to_string())
+    })
 }
 
 #[ic_cdk::update]
-pub async fn reject_token_request(user: Principal) -> Result<String, String> {
+pub async fn set_token_canister(token_canister_id: Principal) -> Result<String, FactoryError> {
     let caller = ic_cdk::caller();
     
     STATE.with(|state| {
-        let state = state.borrow();
-        if caller != state.admin {
-            return Err("Not authorized".to_string());
-        }
+        let mut state = state.borrow_mut();
+        
+        state.ensure_role(caller, Role::SuperAdmin)?;
         
-        if !state.faucet_requests.contains_key(&user) {
-            return Err("Request not found".to_string());
-        }        
-        Ok(())
+        state.update_config(|config| config.token_canister_id = token_canister_id);
+        Ok::<_, FactoryError>(())
     })?;
 
-    STATE.with(|state| {
-        state.borrow_mut().faucet_requests.remove(&user);
-    });
-
-    Ok("Request rejected".to_string())
+    crate::pricing::prime_platform_decimals();
+    Ok(format!("Token canister ID set successfully: {}", token_canister_id))
 }
 
+// None turns profile lookups off; faucet policies that require a profile then reject requests
 #[ic_cdk::update]
-pub async fn transfer_tokens(to: Principal, amount: u32) -> Result<BlockIndex, String> {
+pub fn set_backend_canister(backend_canister_id: Option<Principal>) -> Result<String, FactoryError> {
     let caller = ic_cdk::caller();
-    let token_canister = STATE.with(|state| state.borrow().token_canister_id);
-
-    // Prevent anonymous calls
-    if caller == Principal::anonymous() {
-        return Err("Anonymous calls not allowed".to_string());
-    }
-
-    let transfer_from_args = TransferFromArgs {
-        from: Account::from(caller),
-        to: Account {
-            owner: to,
-            subaccount: None,
-        },
-        amount: Nat::from(amount),
-        memo: None,
-        spender_subaccount: None,
-        fee: None,
-        created_at_time: None,
-    };
-
-    ic_cdk::call::<(TransferFromArgs,), (Result<BlockIndex, TransferFromError>,)>(
-        token_canister,
-        "icrc2_transfer_from",
-        (transfer_from_args,),
-    )
-    .await
-    .map_err(|e| format!("Failed to call ledger: {:?}", e))?
-    .0
-    .map_err(|e| format!("Ledger transfer error: {:?}", e))
-}
-
 
-
-#[ic_cdk::update]
-pub async fn set_token_canister(token_canister_id: Principal) -> Result<String, String> {
-    let caller = ic_cdk::caller();
-    
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        
-        // Check if caller is admin
-        if caller != state.admin {
-            return Err("Not authorized".to_string());
+        state.ensure_role(caller, Role::SuperAdmin)?;
+        if backend_canister_id.is_none() && state.faucet_policy.get().eligibility().require_profile {
+            return Err(FactoryError::InvalidState("The faucet policy requires profiles; turn require_profile off first".to_string()));
         }
-        
-        state.token_canister_id = token_canister_id;
-        Ok(format!("Token canister ID set successfully: {}", token_canister_id.to_string()))
+        state.update_config(|config| config.backend_canister_id = backend_canister_id);
+        Ok("Backend canister updated".to_string())
     })
 }
 
@@ -0,0 +1,595 @@
+use candid::{Nat, Principal};
+use crate::pricing;
+use crate::state_handler::{ensure_role, State, STATE};
+use crate::types::*;
+use ic_cdk::api::caller;
+use std::collections::BTreeSet;
+use std::ops::Bound;
+
+// Every catalog key starts with one of these, followed by the sort or filter value and the
+// token principal. Values are encoded so byte order is their natural order.
+const BY_CREATED: u8 = 0;
+const BY_NAME: u8 = 1;
+const BY_PRICE: u8 = 2;
+const BY_VOLUME: u8 = 3;
+const BY_HOLDERS: u8 = 4;
+const BY_SYMBOL: u8 = 5;
+const BY_TAG: u8 = 6;
+
+const MAX_TAGS: usize = 8;
+const MAX_TAG_LENGTH: usize = 32;
+
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 100;
+// Index entries a single list_tokens call walks before it hands back a cursor, so a filter
+// that matches little of the catalog cannot turn one query into a full scan
+const MAX_SCANNED_ENTRIES: usize = 1_000;
+// Tokens a filter may match for the listing to be built from them instead of the sort index
+const MAX_CANDIDATES: usize = 1_000;
+
+fn sort_kind(sort: TokenSort) -> u8 {
+    match sort {
+        TokenSort::Created => BY_CREATED,
+        TokenSort::Name => BY_NAME,
+        TokenSort::Price => BY_PRICE,
+        TokenSort::Volume => BY_VOLUME,
+        TokenSort::Holders => BY_HOLDERS,
+    }
+}
+
+fn saturating_u128(value: &Nat) -> u128 {
+    u128::try_from(&value.0).unwrap_or(u128::MAX)
+}
+
+// Text values end in a zero byte so that a value sorts before its own extensions
+fn text_value(text: &str) -> Vec<u8> {
+    let mut value: Vec<u8> = text.bytes().filter(|byte| *byte != 0).collect();
+    value.push(0);
+    value
+}
+
+fn key(kind: u8, value: &[u8], token: Principal) -> Vec<u8> {
+    let mut key = Vec::with_capacity(1 + value.len() + token.as_slice().len());
+    key.push(kind);
+    key.extend_from_slice(value);
+    key.extend_from_slice(token.as_slice());
+    key
+}
+
+fn sort_key(kind: u8, token: Principal, metadata: &TokenMetadata, stats: &TokenStats) -> Vec<u8> {
+    let value = match kind {
+        BY_CREATED => metadata.created.to_be_bytes().to_vec(),
+        BY_NAME => text_value(&metadata.name.to_lowercase()),
+        BY_PRICE => saturating_u128(&stats.price).to_be_bytes().to_vec(),
+        BY_VOLUME => saturating_u128(&stats.volume).to_be_bytes().to_vec(),
+        _ => stats.holders.to_be_bytes().to_vec(),
+    };
+    key(kind, &value, token)
+}
+
+fn entry_keys(token: Principal, metadata: &TokenMetadata, stats: &TokenStats) -> Vec<Vec<u8>> {
+    let mut keys: Vec<Vec<u8>> = [BY_CREATED, BY_NAME, BY_PRICE, BY_VOLUME, BY_HOLDERS]
+        .into_iter()
+        .map(|kind| sort_key(kind, token, metadata, stats))
+        .collect();
+    keys.push(key(BY_SYMBOL, &text_value(&metadata.symbol.to_uppercase()), token));
+    for tag in metadata.tags.iter().flatten() {
+        keys.push(key(BY_TAG, &text_value(tag), token));
+    }
+    keys
+}
+
+type IndexEntry = (Vec<u8>, Principal);
+
+// Start of the price index entries at `price`, before any token principal
+fn price_prefix(price: &Nat) -> Vec<u8> {
+    let mut prefix = vec![BY_PRICE];
+    prefix.extend_from_slice(&saturating_u128(price).to_be_bytes());
+    prefix
+}
+
+fn bound_key(bound: &Bound<Vec<u8>>) -> &[u8] {
+    match bound {
+        Bound::Included(key) | Bound::Excluded(key) => key,
+        Bound::Unbounded => &[],
+    }
+}
+
+// Walk index entries until the page has one entry more than `size` or MAX_SCANNED_ENTRIES
+// were looked at. In the latter case the last key looked at is returned to resume from.
+fn scan_entries(
+    entries: impl Iterator<Item = IndexEntry>,
+    size: usize,
+    matching: impl Fn(&IndexEntry) -> bool,
+) -> (Vec<IndexEntry>, Option<Vec<u8>>) {
+    let mut page = Vec::new();
+    let mut last_scanned = None;
+    for (scanned, entry) in entries.enumerate() {
+        if page.len() > size {
+            break;
+        }
+        if scanned == MAX_SCANNED_ENTRIES {
+            return (page, last_scanned);
+        }
+        last_scanned = Some(entry.0.clone());
+        if matching(&entry) {
+            page.push(entry);
+        }
+    }
+    (page, None)
+}
+
+fn stats_of(state: &State, token: Principal) -> TokenStats {
+    state.token_stats.get(&token).unwrap_or_default()
+}
+
+// Apply a change to a token's metadata or stats and move its catalog keys along
+pub fn reindex(state: &mut State, token: Principal, change: impl FnOnce(&mut State)) {
+    if let Some(metadata) = state.tokens.get(&token) {
+        for key in entry_keys(token, &metadata, &stats_of(state, token)) {
+            state.catalog_index.remove(&key);
+        }
+    }
+    change(state);
+    if let Some(metadata) = state.tokens.get(&token) {
+        for key in entry_keys(token, &metadata, &stats_of(state, token)) {
+            state.catalog_index.insert(key, token);
+        }
+    }
+}
+
+fn update_stats(state: &mut State, token: Principal, update: impl FnOnce(&mut TokenStats)) {
+    reindex(state, token, |state| {
+        let mut stats = stats_of(state, token);
+        update(&mut stats);
+        state.token_stats.insert(token, stats);
+    });
+}
+
+// Spot price at the cached supply; None while the platform decimals are not known
+fn current_price(state: &State, token: Principal, metadata: &TokenMetadata) -> Option<Nat> {
+    let platform_decimals = state.ledger_decimals.get(&state.token_canister_id())?;
+    let token_decimals = state.ledger_decimals.get(&token).unwrap_or(metadata.decimals);
+    let supply = Nat::from(state.token_supply.get(&token).unwrap_or_default());
+    pricing::spot_price(&metadata.pricing_model(platform_decimals), &supply, token_decimals).ok()
+}
+
+pub fn refresh_price(state: &mut State, token: Principal) {
+    let Some(metadata) = state.tokens.get(&token) else {
+        return;
+    };
+    let price = current_price(state, token, &metadata).unwrap_or_default();
+    update_stats(state, token, |stats| stats.price = price);
+}
+
+pub fn refresh_prices(state: &mut State) {
+    let tokens: Vec<Principal> = state.tokens.keys().collect();
+    for token in tokens {
+        refresh_price(state, token);
+    }
+}
+
+pub fn add_volume(state: &mut State, token: Principal, amount: &Nat) {
+    update_stats(state, token, |stats| stats.volume += amount.clone());
+}
+
+pub fn add_holding(state: &mut State, token: Principal, holder: Principal, quantity: &Nat) {
+    let held = state.token_positions.get(&(token, holder)).unwrap_or(0);
+    let now_held = held.saturating_add(saturating_u128(quantity));
+    if now_held == 0 {
+        return;
+    }
+    state.token_positions.insert((token, holder), now_held);
+    state.token_holdings.insert((holder, token), ());
+    if held == 0 {
+        update_stats(state, token, |stats| stats.holders += 1);
+    }
+}
+
+pub fn remove_holding(state: &mut State, token: Principal, holder: Principal, quantity: &Nat) {
+    let Some(held) = state.token_positions.get(&(token, holder)) else {
+        return;
+    };
+    match held.saturating_sub(saturating_u128(quantity)) {
+        0 => {
+            state.token_positions.remove(&(token, holder));
+            update_stats(state, token, |stats| stats.holders = stats.holders.saturating_sub(1));
+        }
+        now_held => {
+            state.token_positions.insert((token, holder), now_held);
+        }
+    }
+}
+
+// Recompute stats, positions and keys of every token from the trade log, the sales and the
+// candles, in the order they happened
+pub fn rebuild(state: &mut State) {
+    state.catalog_index.clear_new();
+    state.token_stats.clear_new();
+    state.token_positions.clear_new();
+
+    let mut fills: Vec<(u64, Principal, Principal, Nat, bool)> = state.trades.values()
+        .filter_map(|trade| {
+            let receiver = trade.purchase_id
+                .and_then(|purchase_id| state.purchases.get(&purchase_id))
+                .map(|purchase| purchase.receiver_account().owner)
+                .unwrap_or(trade.buyer);
+            Some((trade.timestamp, trade.token, receiver, trade.quantity?, true))
+        })
+        .collect();
+    fills.extend(state.redemptions.values()
+        .filter(|redemption| redemption.burn_block.is_some())
+        .map(|redemption| (redemption.created_at, redemption.token, redemption.seller, redemption.quantity, false)));
+    fills.sort_by_key(|(timestamp, ..)| *timestamp);
+
+    let tokens: Vec<Principal> = state.tokens.keys().collect();
+    for token in &tokens {
+        let volume = state.hourly_candles.range((*token, 0)..=(*token, u64::MAX))
+            .fold(Nat::from(0u32), |volume, (_, candle)| volume + candle.quote_volume);
+        state.token_stats.insert(*token, TokenStats { volume, ..TokenStats::default() });
+    }
+    for (_, token, holder, quantity, bought) in fills {
+        if bought {
+            add_holding(state, token, holder, &quantity);
+        } else {
+            remove_holding(state, token, holder, &quantity);
+        }
+    }
+    // Adds the keys of every token along with its price
+    refresh_prices(state);
+}
+
+// Lowercase, trimmed and deduplicated; letters, digits and dashes only
+pub fn normalize_tags(tags: Vec<String>) -> Result<Vec<String>, FactoryError> {
+    let mut normalized: Vec<String> = Vec::new();
+    for tag in tags {
+        let tag = tag.trim().to_lowercase();
+        if tag.is_empty() || tag.len() > MAX_TAG_LENGTH {
+            return Err(FactoryError::InvalidArgument(format!("Tags must be between 1 and {} characters", MAX_TAG_LENGTH)));
+        }
+        if !tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(FactoryError::InvalidArgument(format!("Tag '{}' may only contain letters, digits and dashes", tag)));
+        }
+        if !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+    if normalized.len() > MAX_TAGS {
+        return Err(FactoryError::InvalidArgument(format!("A token can have at most {} tags", MAX_TAGS)));
+    }
+    Ok(normalized)
+}
+
+// Replace the tags of a token; allowed for its owner and moderators
+#[ic_cdk::update]
+pub fn set_token_tags(token: Principal, tags: Vec<String>) -> Result<Vec<String>, FactoryError> {
+    let metadata = STATE.with(|state| state.borrow().tokens.get(&token))
+        .ok_or(FactoryError::TokenNotFound(token))?;
+    if metadata.owner != caller() {
+        ensure_role(caller(), Role::Moderator)?;
+    }
+    let tags = normalize_tags(tags)?;
+
+    STATE.with(|state| {
+        reindex(&mut state.borrow_mut(), token, |state| {
+            let mut metadata = metadata;
+            metadata.tags = Some(tags.clone());
+            state.tokens.insert(token, metadata);
+        });
+    });
+    Ok(tags)
+}
+
+// Tokens whose catalog keys of `kind` start with `prefix`
+// Tokens whose index value under `kind` starts with `prefix`, or None if there are more than
+// MAX_CANDIDATES of them
+fn tokens_with_prefix(state: &State, kind: u8, prefix: &[u8]) -> Option<BTreeSet<Principal>> {
+    let start = key(kind, prefix, Principal::management_canister());
+    let tokens: BTreeSet<Principal> = state.catalog_index.range(start..)
+        .take_while(|(key, _)| key[0] == kind && key[1..].starts_with(prefix))
+        .map(|(_, token)| token)
+        .take(MAX_CANDIDATES + 1)
+        .collect();
+    (tokens.len() <= MAX_CANDIDATES).then_some(tokens)
+}
+
+fn intersect(candidates: Option<BTreeSet<Principal>>, tokens: Option<BTreeSet<Principal>>) -> Option<BTreeSet<Principal>> {
+    match (candidates, tokens) {
+        (Some(candidates), Some(tokens)) => Some(candidates.intersection(&tokens).copied().collect()),
+        (candidates, tokens) => candidates.or(tokens),
+    }
+}
+
+// Owner, symbol and tag filters of a listing
+struct Filters {
+    owner: Option<Principal>,
+    symbol_prefix: Option<Vec<u8>>,
+    tags: Vec<String>,
+}
+
+impl Filters {
+    fn new(args: &ListTokensArgs) -> Result<Self, FactoryError> {
+        let symbol_prefix = match &args.symbol_prefix {
+            Some(prefix) => {
+                let prefix: Vec<u8> = prefix.to_uppercase().bytes().filter(|byte| *byte != 0).collect();
+                if prefix.is_empty() {
+                    return Err(FactoryError::InvalidArgument("Symbol prefix cannot be empty".to_string()));
+                }
+                Some(prefix)
+            }
+            None => None,
+        };
+        Ok(Self {
+            owner: args.owner,
+            symbol_prefix,
+            tags: normalize_tags(args.tags.clone().unwrap_or_default())?,
+        })
+    }
+
+    fn matches(&self, metadata: &TokenMetadata) -> bool {
+        self.owner.is_none_or(|owner| metadata.owner == owner)
+            && self.symbol_prefix.as_ref().is_none_or(|prefix| text_value(&metadata.symbol.to_uppercase()).starts_with(prefix))
+            && self.tags.iter().all(|tag| metadata.tags.as_ref().is_some_and(|tags| tags.contains(tag)))
+    }
+
+    // Tokens the filters narrow the listing down to through their indexes, or None if no
+    // filter is set or every one set matches more than MAX_CANDIDATES tokens
+    fn candidates(&self, state: &State) -> Option<BTreeSet<Principal>> {
+        let mut candidates = None;
+        if let Some(owner) = self.owner {
+            candidates = intersect(candidates, Some(state.talent_token_map.get(&owner).into_iter().collect()));
+        }
+        if let Some(prefix) = &self.symbol_prefix {
+            candidates = intersect(candidates, tokens_with_prefix(state, BY_SYMBOL, prefix));
+        }
+        for tag in &self.tags {
+            candidates = intersect(candidates, tokens_with_prefix(state, BY_TAG, &text_value(tag)));
+        }
+        candidates
+    }
+}
+
+// Browse the catalog. Owner, symbol and tag filters that match few enough tokens narrow the
+// listing through their indexes first; otherwise the sort index is walked from the cursor
+// with the filters checked per entry. A filtered walk stops after MAX_SCANNED_ENTRIES, so a
+// page can come back short or empty while next_cursor is still set.
+#[ic_cdk::query]
+pub fn list_tokens(args: ListTokensArgs) -> Result<TokenListPage, FactoryError> {
+    let size = match args.limit.unwrap_or(DEFAULT_PAGE_SIZE) {
+        0 => return Err(FactoryError::InvalidArgument("Limit must be greater than zero".to_string())),
+        limit if limit > MAX_PAGE_SIZE => return Err(FactoryError::InvalidArgument(format!("Limit cannot exceed {}", MAX_PAGE_SIZE))),
+        limit => limit as usize,
+    };
+    let kind = sort_kind(args.sort.unwrap_or(TokenSort::Created));
+    let descending = args.descending.unwrap_or(false);
+    if let Some(cursor) = &args.cursor {
+        if cursor.first() != Some(&kind) {
+            return Err(FactoryError::InvalidArgument("The cursor belongs to a different sort".to_string()));
+        }
+    }
+
+    let filters = Filters::new(&args)?;
+
+    STATE.with(|state| {
+        let state = state.borrow();
+        let in_price_range = |stats: &TokenStats| {
+            args.min_price.as_ref().is_none_or(|min| stats.price >= *min)
+                && args.max_price.as_ref().is_none_or(|max| stats.price <= *max)
+        };
+
+        let (mut page, resume_after) = match filters.candidates(&state) {
+            Some(candidates) => {
+                let mut keyed: Vec<IndexEntry> = candidates.into_iter()
+                    .filter_map(|token| {
+                        let metadata = state.tokens.get(&token)?;
+                        let stats = stats_of(&state, token);
+                        (filters.matches(&metadata) && in_price_range(&stats))
+                            .then(|| (sort_key(kind, token, &metadata, &stats), token))
+                    })
+                    .filter(|(key, _)| match &args.cursor {
+                        Some(cursor) if descending => key < cursor,
+                        Some(cursor) => key > cursor,
+                        None => true,
+                    })
+                    .collect();
+                keyed.sort();
+                if descending {
+                    keyed.reverse();
+                }
+                keyed.truncate(size + 1);
+                (keyed, None)
+            }
+            None => {
+                let mut lower = match &args.cursor {
+                    Some(cursor) if !descending => Bound::Excluded(cursor.clone()),
+                    _ => Bound::Included(vec![kind]),
+                };
+                let mut upper = match &args.cursor {
+                    Some(cursor) if descending => Bound::Excluded(cursor.clone()),
+                    _ => Bound::Excluded(vec![kind + 1]),
+                };
+                // Sorted by price, the price range is a range of the sort index itself
+                if kind == BY_PRICE {
+                    if let Some(min) = &args.min_price {
+                        let from = price_prefix(min);
+                        if from.as_slice() > bound_key(&lower) {
+                            lower = Bound::Included(from);
+                        }
+                    }
+                    if let Some(max) = &args.max_price {
+                        let until = match saturating_u128(max).checked_add(1) {
+                            Some(next) => price_prefix(&Nat::from(next)),
+                            None => vec![BY_PRICE + 1],
+                        };
+                        if until.as_slice() < bound_key(&upper) {
+                            upper = Bound::Excluded(until);
+                        }
+                    }
+                }
+                if bound_key(&lower) >= bound_key(&upper) {
+                    (Vec::new(), None)
+                } else {
+                    let entries = state.catalog_index.range((lower, upper));
+                    let filtered = filters.owner.is_some() || filters.symbol_prefix.is_some() || !filters.tags.is_empty();
+                    let priced = args.min_price.is_some() || args.max_price.is_some();
+                    let matching = |(_, token): &IndexEntry| {
+                        (!filtered || state.tokens.get(token).is_some_and(|metadata| filters.matches(&metadata)))
+                            && (!priced || in_price_range(&stats_of(&state, *token)))
+                    };
+                    if descending {
+                        scan_entries(entries.rev(), size, matching)
+                    } else {
+                        scan_entries(entries, size, matching)
+                    }
+                }
+            }
+        };
+
+        let next_cursor = if page.len() > size {
+            page.truncate(size);
+            page.last().map(|(key, _)| key.clone())
+        } else {
+            resume_after
+        };
+        let tokens = page.into_iter()
+            .filter_map(|(_, token)| Some(TokenListing {
+                token,
+                metadata: state.tokens.get(&token)?,
+                stats: stats_of(&state, token),
+            }))
+            .collect();
+        Ok(TokenListPage { tokens, next_cursor })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(id: u8) -> Principal {
+        Principal::from_slice(&[id; 10])
+    }
+
+    fn metadata(name: &str, symbol: &str, created: u64, tags: &[&str]) -> TokenMetadata {
+        TokenMetadata {
+            name: name.to_string(),
+            symbol: symbol.to_string(),
+            decimals: 8,
+            token_price: 1,
+            owner: token(99),
+            logo: None,
+            created,
+            wasm_version: None,
+            pricing: None,
+            tags: Some(tags.iter().map(|tag| tag.to_string()).collect()),
+            index_canister_id: None,
+            index_wasm_version: None,
+        }
+    }
+
+    fn stats(price: u128, volume: u128, holders: u64) -> TokenStats {
+        TokenStats { price: Nat::from(price), volume: Nat::from(volume), holders }
+    }
+
+    #[test]
+    fn keys_start_with_their_kind() {
+        let keys = entry_keys(token(1), &metadata("Alice", "ALC", 5, &["music"]), &stats(1, 2, 3));
+        let kinds: Vec<u8> = keys.iter().map(|key| key[0]).collect();
+        assert_eq!(kinds, vec![BY_CREATED, BY_NAME, BY_PRICE, BY_VOLUME, BY_HOLDERS, BY_SYMBOL, BY_TAG]);
+        assert!(keys.iter().all(|key| key.ends_with(token(1).as_slice())));
+    }
+
+    #[test]
+    fn numeric_keys_sort_by_value() {
+        let low = stats(9, 255, 1);
+        let high = stats(256, 1 << 70, 300);
+        let meta = metadata("a", "A", 0, &[]);
+        for kind in [BY_PRICE, BY_VOLUME, BY_HOLDERS] {
+            assert!(sort_key(kind, token(2), &meta, &low) < sort_key(kind, token(1), &meta, &high));
+        }
+        assert!(sort_key(BY_CREATED, token(2), &metadata("a", "A", 9, &[]), &low)
+            < sort_key(BY_CREATED, token(1), &metadata("a", "A", 256, &[]), &low));
+    }
+
+    #[test]
+    fn equal_values_sort_by_token() {
+        let meta = metadata("a", "A", 0, &[]);
+        let same = stats(7, 7, 7);
+        assert!(sort_key(BY_PRICE, token(1), &meta, &same) < sort_key(BY_PRICE, token(2), &meta, &same));
+    }
+
+    #[test]
+    fn names_sort_case_insensitively_and_before_their_extensions() {
+        let key_of = |name: &str, id: u8| sort_key(BY_NAME, token(id), &metadata(name, "A", 0, &[]), &TokenStats::default());
+        assert!(key_of("ab", 255) < key_of("abc", 1));
+        assert!(key_of("Abc", 2) < key_of("abd", 1));
+    }
+
+    #[test]
+    fn prices_above_u128_saturate() {
+        let huge = Nat::from(u128::MAX) * 2u32;
+        assert_eq!(saturating_u128(&huge), u128::MAX);
+        assert_eq!(price_prefix(&huge), price_prefix(&Nat::from(u128::MAX)));
+    }
+
+    #[test]
+    fn price_prefix_brackets_the_price_keys() {
+        let meta = metadata("a", "A", 0, &[]);
+        let key = sort_key(BY_PRICE, token(1), &meta, &stats(500, 0, 0));
+        assert!(price_prefix(&Nat::from(500u32)).as_slice() <= key.as_slice());
+        assert!(key.as_slice() < price_prefix(&Nat::from(501u32)).as_slice());
+        assert!(key.as_slice() > price_prefix(&Nat::from(499u32)).as_slice());
+    }
+
+    #[test]
+    fn tags_are_normalized() {
+        assert_eq!(normalize_tags(vec![" Music ".to_string(), "music".to_string(), "hip-hop".to_string()]).unwrap(), vec!["music", "hip-hop"]);
+        assert!(normalize_tags(vec!["".to_string()]).is_err());
+        assert!(normalize_tags(vec!["no spaces".to_string()]).is_err());
+        assert!(normalize_tags((0..=MAX_TAGS).map(|i| format!("tag{}", i)).collect()).is_err());
+    }
+
+    #[test]
+    fn filters_check_symbol_prefix_and_every_tag() {
+        let args = ListTokensArgs {
+            symbol_prefix: Some("al".to_string()),
+            tags: Some(vec!["Music".to_string(), "art".to_string()]),
+            ..Default::default()
+        };
+        let filters = Filters::new(&args).unwrap();
+        assert!(filters.matches(&metadata("x", "ALC", 0, &["music", "art", "film"])));
+        assert!(!filters.matches(&metadata("x", "ALC", 0, &["music"])));
+        assert!(!filters.matches(&metadata("x", "BOB", 0, &["music", "art"])));
+    }
+
+    #[test]
+    fn empty_symbol_prefix_is_refused() {
+        let args = ListTokensArgs { symbol_prefix: Some(String::new()), ..Default::default() };
+        assert!(Filters::new(&args).is_err());
+    }
+
+    #[test]
+    fn intersect_ignores_filters_without_candidates() {
+        let set = |ids: &[u8]| Some(ids.iter().map(|id| token(*id)).collect::<BTreeSet<_>>());
+        assert_eq!(intersect(set(&[1, 2]), set(&[2, 3])), set(&[2]));
+        assert_eq!(intersect(None, set(&[1])), set(&[1]));
+        assert_eq!(intersect(set(&[1]), None), set(&[1]));
+        assert_eq!(intersect(None, None), None);
+    }
+
+    #[test]
+    fn scan_stops_after_the_cap_with_a_cursor() {
+        let entries = (0..MAX_SCANNED_ENTRIES as u32 + 10).map(|i| (i.to_be_bytes().to_vec(), token(1)));
+        let (page, resume_after) = scan_entries(entries, 10, |_| false);
+        assert!(page.is_empty());
+        assert_eq!(resume_after, Some((MAX_SCANNED_ENTRIES as u32 - 1).to_be_bytes().to_vec()));
+    }
+
+    #[test]
+    fn scan_fills_one_entry_past_the_page() {
+        let entries = (0..100u32).map(|i| (i.to_be_bytes().to_vec(), token(1)));
+        let (page, resume_after) = scan_entries(entries, 10, |(key, _)| key[3] % 2 == 0);
+        assert_eq!(page.len(), 11);
+        assert_eq!(resume_after, None);
+    }
+}
@@ -0,0 +1,502 @@
+use candid::{Nat, Principal};
+use crate::faucet_eligibility::{self, MAX_POW_DIFFICULTY_BITS};
+use crate::idempotency;
+use crate::ledger::{self, FAUCET_TREASURY_SUBACCOUNT};
+use crate::state_handler::{ensure_role, InFlightGuard, State, STATE};
+use crate::types::*;
+use futures::future::join_all;
+use futures::stream::{self, StreamExt};
+use ic_cdk::api::caller;
+use ic_cdk_timers::TimerId;
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
+use icrc_ledger_types::icrc1::transfer::BlockIndex;
+use std::cell::RefCell;
+use std::time::Duration;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const DAY_NANOS: u64 = 24 * 60 * 60 * NANOS_PER_SECOND;
+const MIN_AUTO_APPROVAL_INTERVAL_SECONDS: u64 = 10;
+// Requests paid per auto-approval tick
+const AUTO_APPROVAL_BATCH_SIZE: usize = 20;
+const AUTO_APPROVAL: &str = "Faucet auto-approval";
+const MAX_BATCH_REQUESTS: usize = 100;
+// Ledger transfers in flight at once during a batch approval
+const BATCH_FAN_OUT: usize = 10;
+const FAUCET_TOP_UP: &str = "Faucet top-up";
+const FAUCET_PAYMENT: &str = "Faucet payment";
+
+thread_local! {
+    static AUTO_APPROVAL_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+}
+
+pub fn insert_request(state: &mut State, requester: Principal, to: Option<Account>, amount: Nat, requested_at: u64, status: FaucetStatus) -> u64 {
+    let id = state.faucet_requests.last_key_value().map(|(id, _)| id + 1).unwrap_or(0);
+    state.faucet_requests.insert(id, FaucetRequest {
+        id,
+        requester,
+        amount,
+        requested_at,
+        status,
+        reviewed_by: None,
+        reviewed_at: None,
+        payment_block: None,
+        last_error: None,
+        to,
+        payment_started_at: None,
+    });
+    state.faucet_requests_by_user.insert((requester, id), ());
+    id
+}
+
+fn get_request(request_id: u64) -> Result<FaucetRequest, FactoryError> {
+    STATE.with(|state| {
+        state.borrow().faucet_requests.get(&request_id)
+            .ok_or(FactoryError::FaucetRequestNotFound(request_id))
+    })
+}
+
+fn save_request(request: FaucetRequest) {
+    STATE.with(|state| {
+        state.borrow_mut().faucet_requests.insert(request.id, request);
+    });
+}
+
+fn requests_of(state: &State, user: Principal) -> Vec<FaucetRequest> {
+    state.faucet_requests_by_user
+        .range((user, 0)..=(user, u64::MAX))
+        .filter_map(|((_, id), _)| state.faucet_requests.get(&id))
+        .collect()
+}
+
+// Requests that count against a principal's limits: everything not rejected or failed
+fn counts_against_limits(request: &FaucetRequest) -> bool {
+    matches!(request.status, FaucetStatus::Pending | FaucetStatus::Approved | FaucetStatus::Paid)
+}
+
+fn ensure_within_limit(limit: FaucetLimit, cap: &Option<Nat>, used: Nat, amount: &Nat) -> Result<(), FactoryError> {
+    match cap {
+        Some(cap) if used.clone() + amount.clone() > *cap => {
+            let available = if *cap > used { cap.clone() - used } else { Nat::from(0u32) };
+            Err(FactoryError::FaucetLimitExceeded { limit, available })
+        }
+        _ => Ok(()),
+    }
+}
+
+fn check_policy(state: &State, requester: Principal, amount: &Nat, now: u64) -> Result<(), FactoryError> {
+    let policy = state.faucet_policy.get();
+    let history: Vec<_> = requests_of(state, requester).into_iter().filter(counts_against_limits).collect();
+
+    if let Some(last) = history.iter().map(|request| request.requested_at).max() {
+        let retry_at = last.saturating_add(policy.cooldown_seconds.saturating_mul(NANOS_PER_SECOND));
+        if now < retry_at {
+            return Err(FactoryError::FaucetCooldown { retry_at });
+        }
+    }
+
+    ensure_within_limit(FaucetLimit::PerRequest, &policy.max_per_request, Nat::from(0u32), amount)?;
+
+    let day_start = now.saturating_sub(DAY_NANOS);
+    let today = history.iter()
+        .filter(|request| request.requested_at > day_start)
+        .fold(Nat::from(0u32), |total, request| total + request.amount.clone());
+    ensure_within_limit(FaucetLimit::PerDay, &policy.max_per_day, today, amount)?;
+
+    let lifetime = history.iter().fold(Nat::from(0u32), |total, request| total + request.amount.clone());
+    ensure_within_limit(FaucetLimit::Lifetime, &policy.max_lifetime, lifetime, amount)
+}
+
+fn check_new_request(state: &State, requester: Principal, amount: &Nat, now: u64) -> Result<(), FactoryError> {
+    if let Some(pending) = requests_of(state, requester).into_iter().find(|request| request.status == FaucetStatus::Pending) {
+        return Err(FactoryError::InProgress { operation: "Faucet request".to_string(), id: pending.id });
+    }
+    check_policy(state, requester, amount, now)
+}
+
+// `amount` is in platform base units. pow_nonce is only needed when the faucet policy asks for a
+// proof of work; `to` defaults to the caller's default account
+#[ic_cdk::update]
+pub async fn send_token_faucet_request(amount: Nat, pow_nonce: Option<u64>, to: Option<Account>) -> Result<u64, FactoryError> {
+    let caller = caller();
+
+    if caller == Principal::anonymous() {
+        return Err(FactoryError::AnonymousCaller);
+    }
+    if amount == 0u32 {
+        return Err(FactoryError::InvalidArgument("Amount must be greater than zero".to_string()));
+    }
+
+    STATE.with(|state| {
+        let state = state.borrow();
+        check_new_request(&state, caller, &amount, ic_cdk::api::time())?;
+        faucet_eligibility::check_local(&state, caller, pow_nonce)
+    })?;
+
+    faucet_eligibility::check_profile(caller).await?;
+
+    // Another request of the caller may have landed while the backend was asked
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let now = ic_cdk::api::time();
+        check_new_request(&state, caller, &amount, now)?;
+        Ok(insert_request(&mut state, caller, to, amount, now, FaucetStatus::Pending))
+    })
+}
+
+// Approve a pending request, or a failed one again, and pay it from the faucet treasury.
+// Accepting an approved request retries a payment whose outcome was unknown.
+#[ic_cdk::update]
+pub async fn accept_token_request(request_id: u64) -> Result<FaucetRequest, FactoryError> {
+    let reviewer = caller();
+    ensure_role(reviewer, Role::FaucetOperator)?;
+
+    let ledger = STATE.with(|state| state.borrow().token_canister_id());
+    accept(request_id, reviewer, ledger).await
+}
+
+async fn accept(request_id: u64, reviewer: Principal, ledger: Principal) -> Result<FaucetRequest, FactoryError> {
+    let _guard = InFlightGuard::acquire(FAUCET_PAYMENT, request_id)?;
+    let request = approve(request_id, reviewer)?;
+    let result = pay_from_treasury(ledger, &request).await;
+    finish_payment(request, result)
+}
+
+async fn pay_from_treasury(ledger: Principal, request: &FaucetRequest) -> Result<BlockIndex, FactoryError> {
+    let first_attempt_at = request.payment_started_at.or(request.reviewed_at).unwrap_or(request.requested_at);
+    let dedup = ledger::Dedup::new(&format!("faucet/{}/pay", request.id), first_attempt_at);
+    ledger::transfer(ledger, Some(FAUCET_TREASURY_SUBACCOUNT), request.recipient(), request.amount.clone(), dedup).await
+}
+
+fn low_balance_threshold() -> Option<Nat> {
+    STATE.with(|state| state.borrow().config.get().faucet_low_balance_threshold.clone())
+}
+
+// Mark the request approved before paying, so a second call cannot pay it again
+fn approve(request_id: u64, reviewer: Principal) -> Result<FaucetRequest, FactoryError> {
+    let mut request = get_request(request_id)?;
+    if !matches!(request.status, FaucetStatus::Pending | FaucetStatus::Approved | FaucetStatus::Failed) {
+        return Err(FactoryError::InvalidState(format!("Faucet request {} is {:?}", request_id, request.status)));
+    }
+    let now = ic_cdk::api::time();
+    // Requests that failed before the start was recorded were paid with their review time
+    request.payment_started_at.get_or_insert(request.reviewed_at.unwrap_or(now));
+    request.status = FaucetStatus::Approved;
+    request.reviewed_by = Some(reviewer);
+    request.reviewed_at = Some(now);
+    request.last_error = None;
+    save_request(request.clone());
+    Ok(request)
+}
+
+fn finish_payment(mut request: FaucetRequest, result: Result<BlockIndex, FactoryError>) -> Result<FaucetRequest, FactoryError> {
+    match &result {
+        Ok(block) => {
+            request.status = FaucetStatus::Paid;
+            request.payment_block = Some(block.clone());
+        }
+        // A failed call may still have paid, so the request stays approved for a retry
+        Err(e) => {
+            if ledger::is_rejection(e) {
+                request.status = FaucetStatus::Failed;
+            }
+            request.last_error = Some(e.to_string());
+        }
+    }
+    save_request(request.clone());
+    result.map(|_| request)
+}
+
+#[ic_cdk::update]
+pub fn reject_token_request(request_id: u64) -> Result<FaucetRequest, FactoryError> {
+    let reviewer = caller();
+    ensure_role(reviewer, Role::FaucetOperator)?;
+    reject(request_id, reviewer)
+}
+
+fn reject(request_id: u64, reviewer: Principal) -> Result<FaucetRequest, FactoryError> {
+    let mut request = get_request(request_id)?;
+    if !matches!(request.status, FaucetStatus::Pending | FaucetStatus::Failed) {
+        return Err(FactoryError::InvalidState(format!("Faucet request {} is {:?}", request_id, request.status)));
+    }
+    request.status = FaucetStatus::Rejected;
+    request.reviewed_by = Some(reviewer);
+    request.reviewed_at = Some(ic_cdk::api::time());
+    save_request(request.clone());
+    Ok(request)
+}
+
+// Resolve a batch selection to request ids, capped at MAX_BATCH_REQUESTS. Filters take the
+// oldest matching requests first, so repeated calls work through the queue.
+fn select_requests(state: &State, selection: &FaucetBatchSelection) -> Result<Vec<u64>, FactoryError> {
+    let ids: Vec<u64> = match selection {
+        FaucetBatchSelection::Requests(ids) => ids.clone(),
+        FaucetBatchSelection::Requesters(requesters) => requesters.iter()
+            .flat_map(|requester| requests_of(state, *requester))
+            .filter(|request| request.status == FaucetStatus::Pending)
+            .map(|request| request.id)
+            .collect(),
+        FaucetBatchSelection::Pending(filter) => {
+            return Ok(state.faucet_requests.values()
+                .filter(|request| request.status == FaucetStatus::Pending && filter.matches(request))
+                .map(|request| request.id)
+                .take(MAX_BATCH_REQUESTS)
+                .collect());
+        }
+    };
+    if ids.len() > MAX_BATCH_REQUESTS {
+        return Err(FactoryError::InvalidArgument(format!("A batch can hold at most {} requests", MAX_BATCH_REQUESTS)));
+    }
+    Ok(ids)
+}
+
+#[ic_cdk::update]
+pub async fn batch_accept_token_requests(selection: FaucetBatchSelection) -> Result<Vec<FaucetBatchOutcome>, FactoryError> {
+    let reviewer = caller();
+    ensure_role(reviewer, Role::FaucetOperator)?;
+
+    let (ledger, ids) = STATE.with(|state| {
+        let state = state.borrow();
+        select_requests(&state, &selection).map(|ids| (state.token_canister_id(), ids))
+    })?;
+
+    // At most BATCH_FAN_OUT transfers are in flight at once
+    Ok(stream::iter(ids)
+        .map(|request_id| async move {
+            FaucetBatchOutcome {
+                request_id,
+                result: accept(request_id, reviewer, ledger).await,
+            }
+        })
+        .buffered(BATCH_FAN_OUT)
+        .collect()
+        .await)
+}
+
+#[ic_cdk::update]
+pub fn batch_reject_token_requests(selection: FaucetBatchSelection) -> Result<Vec<FaucetBatchOutcome>, FactoryError> {
+    let reviewer = caller();
+    ensure_role(reviewer, Role::FaucetOperator)?;
+
+    let ids = STATE.with(|state| select_requests(&state.borrow(), &selection))?;
+    Ok(ids.into_iter()
+        .map(|request_id| FaucetBatchOutcome {
+            request_id,
+            result: reject(request_id, reviewer),
+        })
+        .collect())
+}
+
+#[ic_cdk::query]
+pub fn get_faucet_request(request_id: u64) -> Result<FaucetRequest, FactoryError> {
+    let request = get_request(request_id)?;
+    if request.requester != caller() {
+        ensure_role(caller(), Role::FaucetOperator)?;
+    }
+    Ok(request)
+}
+
+#[ic_cdk::query]
+pub fn get_my_faucet_requests() -> Result<Vec<FaucetRequest>, FactoryError> {
+    let caller = caller();
+    STATE.with(|state| Ok(requests_of(&state.borrow(), caller)))
+}
+
+#[ic_cdk::query]
+pub fn get_user_faucet_requests(user: Principal) -> Result<Vec<FaucetRequest>, FactoryError> {
+    ensure_role(caller(), Role::FaucetOperator)?;
+    STATE.with(|state| Ok(requests_of(&state.borrow(), user)))
+}
+
+// The review queue, optionally narrowed to one status
+#[ic_cdk::query]
+pub fn get_faucet_requests(status: Option<FaucetStatus>) -> Result<Vec<FaucetRequest>, FactoryError> {
+    ensure_role(caller(), Role::FaucetOperator)?;
+
+    STATE.with(|state| {
+        Ok(state.borrow().faucet_requests.values()
+            .filter(|request| status.as_ref().is_none_or(|status| request.status == *status))
+            .collect())
+    })
+}
+
+// Restart the auto-approval timer with the current policy; timers are lost on upgrade
+pub fn schedule_auto_approval() {
+    let policy = STATE.with(|state| state.borrow().faucet_policy.get().clone());
+    AUTO_APPROVAL_TIMER.with(|timer| {
+        if let Some(timer_id) = timer.borrow_mut().take() {
+            ic_cdk_timers::clear_timer(timer_id);
+        }
+        if policy.auto_approve_up_to.is_some() {
+            let interval = Duration::from_secs(policy.auto_approval_interval_seconds);
+            let timer_id = ic_cdk_timers::set_timer_interval(interval, || ic_cdk::spawn(run_auto_approval()));
+            *timer.borrow_mut() = Some(timer_id);
+        }
+    });
+}
+
+// Pay pending requests under the auto-approval threshold from the faucet treasury, as long
+// as that keeps the treasury above its low-balance threshold
+async fn run_auto_approval() {
+    let Ok(_guard) = InFlightGuard::acquire(AUTO_APPROVAL, 0) else {
+        return;
+    };
+    let (ledger, threshold) = STATE.with(|state| {
+        let state = state.borrow();
+        (state.token_canister_id(), state.faucet_policy.get().auto_approve_up_to.clone())
+    });
+    let Some(threshold) = threshold else {
+        return;
+    };
+
+    let candidates: Vec<_> = STATE.with(|state| {
+        state.borrow().faucet_requests.values()
+            .filter(|request| request.status == FaucetStatus::Pending && request.amount <= threshold)
+            .take(AUTO_APPROVAL_BATCH_SIZE)
+            .collect()
+    });
+    if candidates.is_empty() {
+        return;
+    }
+
+    let (balance, fee) = match futures::try_join!(
+        ledger::balance_of(ledger, ledger::factory_account(FAUCET_TREASURY_SUBACCOUNT)),
+        ledger::fee(ledger),
+    ) {
+        Ok(result) => result,
+        Err(e) => {
+            ic_cdk::println!("Faucet auto-approval skipped: {}", e);
+            return;
+        }
+    };
+    let floor = low_balance_threshold().unwrap_or_default();
+
+    // Approve only what the treasury can pay without dropping under the threshold
+    let factory = ic_cdk::id();
+    let mut remaining = balance;
+    let mut requests = Vec::new();
+    for request in candidates {
+        let cost = request.amount.clone() + fee.clone();
+        if remaining < cost.clone() + floor.clone() {
+            ic_cdk::println!("Faucet treasury is low, auto-approval paused");
+            break;
+        }
+        if let Ok(request) = approve(request.id, factory) {
+            remaining -= cost;
+            requests.push(request);
+        }
+    }
+
+    join_all(requests.into_iter().map(|request| async move {
+        let result = pay_from_treasury(ledger, &request).await;
+        let id = request.id;
+        if let Err(e) = finish_payment(request, result) {
+            ic_cdk::println!("Auto-approved faucet request {} failed: {}", id, e);
+        }
+    }))
+    .await;
+}
+
+// Balance is read from the ledger, so this is an update call like get_total_supply
+#[ic_cdk::update]
+pub async fn get_faucet_treasury() -> Result<FaucetTreasury, FactoryError> {
+    let ledger = STATE.with(|state| state.borrow().token_canister_id());
+    let account = ledger::factory_account(FAUCET_TREASURY_SUBACCOUNT);
+    let balance = ledger::balance_of(ledger, account).await?;
+    let low_balance_threshold = low_balance_threshold();
+    let is_low = low_balance_threshold.as_ref().is_some_and(|threshold| balance < *threshold);
+
+    Ok(FaucetTreasury {
+        account,
+        balance,
+        low_balance_threshold,
+        is_low,
+    })
+}
+
+// Move approved funds from the caller into the faucet treasury. The account from
+// get_faucet_treasury can also be funded with a plain transfer. Retrying with the same
+// idempotency key returns the block of the first top-up instead of charging again.
+#[ic_cdk::update]
+pub async fn top_up_faucet_treasury(amount: Nat, from_subaccount: Option<Subaccount>, idempotency_key: Option<String>) -> Result<Nat, FactoryError> {
+    let caller = caller();
+    if caller == Principal::anonymous() {
+        return Err(FactoryError::AnonymousCaller);
+    }
+    if amount == 0u32 {
+        return Err(FactoryError::InvalidArgument("Amount must be greater than zero".to_string()));
+    }
+
+    let dedup = match &idempotency_key {
+        Some(key) => {
+            idempotency::validate_key(key)?;
+            let processed = STATE.with(|state| {
+                let mut state = state.borrow_mut();
+                idempotency::find(&state, caller, FAUCET_TOP_UP, key)
+                    .unwrap_or_else(|| idempotency::remember(&mut state, caller, FAUCET_TOP_UP, key, None))
+            });
+            let hash = idempotency::key_hash(caller, FAUCET_TOP_UP, key);
+            ledger::Dedup::new(&format!("top-up/{}", hex::encode(hash)), processed.first_seen)
+        }
+        None => {
+            let now = ic_cdk::api::time();
+            ledger::Dedup::new(&format!("top-up/{}/{}", caller, now), now)
+        }
+    };
+
+    let ledger = STATE.with(|state| state.borrow().token_canister_id());
+    let from = Account { owner: caller, subaccount: from_subaccount };
+    ledger::transfer_from(ledger, from, ledger::factory_account(FAUCET_TREASURY_SUBACCOUNT), amount, dedup).await
+}
+
+#[ic_cdk::update]
+pub fn set_faucet_low_balance_threshold(threshold: Option<Nat>) -> Result<String, FactoryError> {
+    ensure_role(caller(), Role::Treasurer)?;
+
+    STATE.with(|state| {
+        state.borrow_mut().update_config(|config| config.faucet_low_balance_threshold = threshold);
+    });
+    Ok("Faucet low-balance threshold updated".to_string())
+}
+
+#[ic_cdk::query]
+pub fn get_faucet_policy() -> Result<FaucetPolicy, FactoryError> {
+    STATE.with(|state| Ok(state.borrow().faucet_policy.get().clone()))
+}
+
+#[ic_cdk::update]
+pub fn set_faucet_policy(policy: FaucetPolicy) -> Result<String, FactoryError> {
+    ensure_role(caller(), Role::SuperAdmin)?;
+
+    if policy.auto_approval_interval_seconds < MIN_AUTO_APPROVAL_INTERVAL_SECONDS {
+        return Err(FactoryError::InvalidArgument(format!(
+            "Auto-approval interval must be at least {} seconds",
+            MIN_AUTO_APPROVAL_INTERVAL_SECONDS
+        )));
+    }
+    if let (Some(threshold), Some(max)) = (&policy.auto_approve_up_to, &policy.max_per_request) {
+        if threshold > max {
+            return Err(FactoryError::InvalidArgument("Auto-approval threshold exceeds the per-request maximum".to_string()));
+        }
+    }
+    if let Some(eligibility) = &policy.eligibility {
+        if eligibility.min_profile_age_seconds.is_some() && !eligibility.require_profile {
+            return Err(FactoryError::InvalidArgument("A minimum profile age needs require_profile".to_string()));
+        }
+        let has_backend = STATE.with(|state| state.borrow().config.get().backend_canister_id.is_some());
+        if eligibility.require_profile && !has_backend {
+            return Err(FactoryError::InvalidState("Profile checks need a backend canister, see set_backend_canister".to_string()));
+        }
+        if eligibility.pow_difficulty_bits.is_some_and(|bits| bits > MAX_POW_DIFFICULTY_BITS) {
+            return Err(FactoryError::InvalidArgument(format!(
+                "Proof-of-work difficulty cannot exceed {} bits",
+                MAX_POW_DIFFICULTY_BITS
+            )));
+        }
+    }
+
+    STATE.with(|state| {
+        state.borrow_mut().faucet_policy.set(policy).expect("Failed to persist faucet policy");
+    });
+    schedule_auto_approval();
+    Ok("Faucet policy updated".to_string())
+}
@@ -0,0 +1,123 @@
+use candid::Principal;
+use crate::ledger;
+use crate::state_handler::{ensure_role, State, STATE};
+use crate::types::*;
+use ic_cdk::api::caller;
+use sha2::{Digest, Sha256};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const POW_DOMAIN: &[u8] = b"ic-talent-faucet";
+pub const MAX_POW_DIFFICULTY_BITS: u8 = 32;
+
+// The prefix covers the requester's request count, so every request needs fresh work
+fn pow_prefix(state: &State, requester: Principal) -> Vec<u8> {
+    let request_count = state.faucet_requests_by_user.range((requester, 0)..=(requester, u64::MAX)).count() as u64;
+    let mut prefix = POW_DOMAIN.to_vec();
+    prefix.extend_from_slice(requester.as_slice());
+    prefix.extend_from_slice(&request_count.to_be_bytes());
+    prefix
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+// Checks that need no call to the backend: verification and proof of work
+pub fn check_local(state: &State, requester: Principal, pow_nonce: Option<u64>) -> Result<(), FactoryError> {
+    let eligibility = state.faucet_policy.get().eligibility();
+
+    if eligibility.require_verified && !state.verified_users.contains_key(&requester) {
+        return Err(FactoryError::FaucetIneligible(FaucetIneligibility::NotVerified));
+    }
+
+    if let Some(difficulty) = eligibility.pow_difficulty_bits.filter(|bits| *bits > 0) {
+        let nonce = pow_nonce.ok_or(FactoryError::FaucetIneligible(FaucetIneligibility::MissingProofOfWork))?;
+        let mut hasher = Sha256::new();
+        hasher.update(pow_prefix(state, requester));
+        hasher.update(nonce.to_be_bytes());
+        if leading_zero_bits(&hasher.finalize()) < u32::from(difficulty) {
+            return Err(FactoryError::FaucetIneligible(FaucetIneligibility::InvalidProofOfWork));
+        }
+    }
+    Ok(())
+}
+
+// Look the requester up in the talent backend when the policy asks for a profile
+pub async fn check_profile(requester: Principal) -> Result<(), FactoryError> {
+    let (eligibility, backend) = STATE.with(|state| {
+        let state = state.borrow();
+        (state.faucet_policy.get().eligibility(), state.config.get().backend_canister_id)
+    });
+    if !eligibility.require_profile {
+        return Ok(());
+    }
+    let backend = backend.ok_or_else(|| FactoryError::InvalidState("No backend canister is configured for profile checks".to_string()))?;
+
+    let (profile,): (Result<BackendUserProfile, String>,) = ic_cdk::call(backend, "get_user_by_id", (requester,))
+        .await
+        .map_err(|e| ledger::call_failed(backend, "get_user_by_id", e))?;
+    let profile = profile.map_err(|_| FactoryError::FaucetIneligible(FaucetIneligibility::NoProfile))?;
+
+    // Profiles created before the backend recorded creation times count as old enough
+    if let (Some(min_age), Some(created_at)) = (eligibility.min_profile_age_seconds, profile.created_at) {
+        let eligible_at = created_at.saturating_add(min_age.saturating_mul(NANOS_PER_SECOND));
+        if ic_cdk::api::time() < eligible_at {
+            return Err(FactoryError::FaucetIneligible(FaucetIneligibility::ProfileTooNew { eligible_at }));
+        }
+    }
+    Ok(())
+}
+
+#[ic_cdk::query]
+pub fn get_faucet_pow_challenge() -> Result<FaucetPowChallenge, FactoryError> {
+    let caller = caller();
+    if caller == Principal::anonymous() {
+        return Err(FactoryError::AnonymousCaller);
+    }
+
+    STATE.with(|state| {
+        let state = state.borrow();
+        Ok(FaucetPowChallenge {
+            prefix: pow_prefix(&state, caller),
+            difficulty_bits: state.faucet_policy.get().eligibility().pow_difficulty_bits.unwrap_or(0),
+        })
+    })
+}
+
+#[ic_cdk::update]
+pub fn set_user_verified(user: Principal, verified: bool) -> Result<String, FactoryError> {
+    let moderator = caller();
+    ensure_role(moderator, Role::Moderator)?;
+
+    if user == Principal::anonymous() {
+        return Err(FactoryError::InvalidArgument("The anonymous principal cannot be verified".to_string()));
+    }
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if verified {
+            state.verified_users.insert(user, VerifiedUser {
+                verified_by: moderator,
+                verified_at: ic_cdk::api::time(),
+            });
+            Ok(format!("{} is verified", user))
+        } else if state.verified_users.remove(&user).is_some() {
+            Ok(format!("{} is no longer verified", user))
+        } else {
+            Err(FactoryError::InvalidState(format!("{} is not verified", user)))
+        }
+    })
+}
+
+#[ic_cdk::query]
+pub fn get_verified_users() -> Result<Vec<(Principal, VerifiedUser)>, FactoryError> {
+    ensure_role(caller(), Role::Moderator)?;
+    STATE.with(|state| Ok(state.borrow().verified_users.iter().collect()))
+}
@@ -0,0 +1,65 @@
+use candid::Principal;
+use crate::ledger::{DEDUP_WINDOW_NANOS, PERMITTED_DRIFT_NANOS};
+use crate::state_handler::State;
+use crate::types::*;
+use sha2::{Digest, Sha256};
+
+const MAX_KEY_LENGTH: usize = 64;
+// Expired keys dropped per new key, so pruning never runs long
+const PRUNE_BATCH_SIZE: usize = 100;
+
+pub fn validate_key(key: &str) -> Result<(), FactoryError> {
+    if key.is_empty() || key.len() > MAX_KEY_LENGTH {
+        return Err(FactoryError::InvalidArgument(format!("Idempotency key must be 1 to {} characters", MAX_KEY_LENGTH)));
+    }
+    Ok(())
+}
+
+// Keys are scoped to the caller and the operation, so two users or two endpoints never collide
+pub fn key_hash(caller: Principal, operation: &str, key: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(caller.as_slice());
+    hasher.update([0]);
+    hasher.update(operation.as_bytes());
+    hasher.update([0]);
+    hasher.update(key.as_bytes());
+    hasher.finalize().into()
+}
+
+fn is_live(processed: &ProcessedKey, now: u64) -> bool {
+    now.saturating_sub(processed.first_seen) < DEDUP_WINDOW_NANOS + PERMITTED_DRIFT_NANOS
+}
+
+pub fn find(state: &State, caller: Principal, operation: &str, key: &str) -> Option<ProcessedKey> {
+    state.processed_keys.get(&key_hash(caller, operation, key))
+        .filter(|processed| is_live(processed, ic_cdk::api::time()))
+}
+
+pub fn remember(state: &mut State, caller: Principal, operation: &str, key: &str, record_id: Option<u64>) -> ProcessedKey {
+    let now = ic_cdk::api::time();
+    prune(state, now);
+
+    let hash = key_hash(caller, operation, key);
+    let processed = ProcessedKey {
+        operation: operation.to_string(),
+        record_id,
+        first_seen: now,
+    };
+    state.processed_keys.insert(hash, processed.clone());
+    state.processed_keys_by_time.insert((now, hash), ());
+    processed
+}
+
+// Past the window the ledger no longer deduplicates, so the key is of no use anymore
+fn prune(state: &mut State, now: u64) {
+    let expired: Vec<_> = state.processed_keys_by_time.keys()
+        .take_while(|(first_seen, _)| now.saturating_sub(*first_seen) >= DEDUP_WINDOW_NANOS + PERMITTED_DRIFT_NANOS)
+        .take(PRUNE_BATCH_SIZE)
+        .collect();
+    for (first_seen, hash) in expired {
+        state.processed_keys_by_time.remove(&(first_seen, hash));
+        if state.processed_keys.get(&hash).is_some_and(|processed| processed.first_seen == first_seen) {
+            state.processed_keys.remove(&hash);
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use candid::{Nat, Principal};
+use crate::state_handler::STATE;
+use crate::types::FactoryError;
+use ic_cdk::api::call::RejectionCode;
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
+use icrc_ledger_types::icrc1::transfer::{BlockIndex, Memo, TransferArg, TransferError};
+use icrc_ledger_types::icrc2::allowance::{Allowance, AllowanceArgs};
+use icrc_ledger_types::icrc2::transfer_from::{TransferFromArgs, TransferFromError};
+use sha2::{Digest, Sha256};
+
+// Factory-owned subaccounts on the platform ledger
+pub const CREATION_ESCROW_SUBACCOUNT: Subaccount = tagged_subaccount(1);
+pub const PURCHASE_ESCROW_SUBACCOUNT: Subaccount = tagged_subaccount(2);
+pub const RESERVE_SUBACCOUNT: Subaccount = tagged_subaccount(3);
+pub const FAUCET_TREASURY_SUBACCOUNT: Subaccount = tagged_subaccount(4);
+
+const fn tagged_subaccount(tag: u8) -> Subaccount {
+    let mut subaccount = [0u8; 32];
+    subaccount[31] = tag;
+    subaccount
+}
+
+pub fn factory_account(subaccount: Subaccount) -> Account {
+    Account {
+        owner: ic_cdk::id(),
+        subaccount: Some(subaccount),
+    }
+}
+
+// ICRC ledgers treat a transfer as a duplicate if one with the same content, memo and
+// created_at_time landed within this window, and reject created_at_time older than it
+pub const DEDUP_WINDOW_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+pub const PERMITTED_DRIFT_NANOS: u64 = 60 * 1_000_000_000;
+
+// Memo and created_at_time that make a retried transfer a duplicate of its first attempt
+#[derive(Clone)]
+pub struct Dedup {
+    pub memo: Memo,
+    pub created_at_time: u64,
+}
+
+impl Dedup {
+    // `seed` names the transfer, e.g. "purchase/7/escrow", and `first_attempt_at` is when the
+    // operation started. Once the window has passed the ledger would reject that time as too
+    // old, so the current time is used instead.
+    pub fn new(seed: &str, first_attempt_at: u64) -> Self {
+        let now = ic_cdk::api::time();
+        let created_at_time = if now.saturating_sub(first_attempt_at) < DEDUP_WINDOW_NANOS {
+            first_attempt_at
+        } else {
+            now
+        };
+        Self {
+            memo: Memo::from(Sha256::digest(seed.as_bytes()).to_vec()),
+            created_at_time,
+        }
+    }
+}
+
+pub fn call_failed(canister: Principal, method: &str, (code, message): (RejectionCode, String)) -> FactoryError {
+    FactoryError::CallFailed {
+        canister,
+        method: method.to_string(),
+        reason: format!("code={:?}, msg={}", code, message),
+    }
+}
+
+// The ledger answered and refused the transfer. A failed call says nothing about whether the
+// transfer went through, so it has to be retried with the same dedup before anything is undone.
+pub fn is_rejection(error: &FactoryError) -> bool {
+    matches!(
+        error,
+        FactoryError::LedgerRejected(_)
+            | FactoryError::TransferRejected(_)
+            | FactoryError::InsufficientAllowance { .. }
+            | FactoryError::InsufficientFunds { .. }
+    )
+}
+
+// Pull funds the owner of `from` has approved for the factory
+// A duplicate of an earlier attempt counts as success and returns the block of that attempt
+pub async fn transfer_from(ledger: Principal, from: Account, to: Account, amount: Nat, dedup: Dedup) -> Result<BlockIndex, FactoryError> {
+    let transfer_from_args = TransferFromArgs {
+        from,
+        to,
+        amount: amount.clone(),
+        memo: Some(dedup.memo),
+        spender_subaccount: None,
+        fee: None,
+        created_at_time: Some(dedup.created_at_time),
+    };
+
+    ic_cdk::call::<(TransferFromArgs,), (Result<BlockIndex, TransferFromError>,)>(
+        ledger,
+        "icrc2_transfer_from",
+        (transfer_from_args,),
+    )
+    .await
+    .map_err(|e| call_failed(ledger, "icrc2_transfer_from", e))?
+    .0
+    .or_else(|e| match e {
+        TransferFromError::Duplicate { duplicate_of } => Ok(duplicate_of),
+        e => Err(e),
+    })
+    .map_err(|e| match e {
+        TransferFromError::InsufficientAllowance { allowance } => {
+            FactoryError::InsufficientAllowance { required: amount, available: allowance }
+        }
+        TransferFromError::InsufficientFunds { balance } => {
+            FactoryError::InsufficientFunds { required: amount, available: balance }
+        }
+        e => FactoryError::LedgerRejected(e),
+    })
+}
+
+// Send funds held by one of the factory's own accounts; duplicates count as success like in transfer_from
+pub async fn transfer(ledger: Principal, from_subaccount: Option<Subaccount>, to: Account, amount: Nat, dedup: Dedup) -> Result<BlockIndex, FactoryError> {
+    let transfer_args = TransferArg {
+        from_subaccount,
+        to,
+        amount: amount.clone(),
+        fee: None,
+        memo: Some(dedup.memo),
+        created_at_time: Some(dedup.created_at_time),
+    };
+
+    ic_cdk::call::<(TransferArg,), (Result<BlockIndex, TransferError>,)>(
+        ledger,
+        "icrc1_transfer",
+        (transfer_args,),
+    )
+    .await
+    .map_err(|e| call_failed(ledger, "icrc1_transfer", e))?
+    .0
+    .or_else(|e| match e {
+        TransferError::Duplicate { duplicate_of } => Ok(duplicate_of),
+        e => Err(e),
+    })
+    .map_err(|e| match e {
+        TransferError::InsufficientFunds { balance } => {
+            FactoryError::InsufficientFunds { required: amount, available: balance }
+        }
+        e => FactoryError::TransferRejected(e),
+    })
+}
+
+pub async fn fee(ledger: Principal) -> Result<Nat, FactoryError> {
+    ic_cdk::call::<(), (Nat,)>(ledger, "icrc1_fee", ())
+        .await
+        .map(|(fee,)| fee)
+        .map_err(|e| call_failed(ledger, "icrc1_fee", e))
+}
+
+// Decimals never change once a ledger is installed, so they are read once and kept
+pub fn cached_decimals(ledger: Principal) -> Option<u8> {
+    STATE.with(|state| state.borrow().ledger_decimals.get(&ledger))
+}
+
+pub async fn decimals(ledger: Principal) -> Result<u8, FactoryError> {
+    if let Some(decimals) = cached_decimals(ledger) {
+        return Ok(decimals);
+    }
+
+    let (decimals,): (u8,) = ic_cdk::call(ledger, "icrc1_decimals", ())
+        .await
+        .map_err(|e| call_failed(ledger, "icrc1_decimals", e))?;
+    STATE.with(|state| {
+        state.borrow_mut().ledger_decimals.insert(ledger, decimals);
+    });
+    Ok(decimals)
+}
+
+pub async fn balance_of(ledger: Principal, account: Account) -> Result<Nat, FactoryError> {
+    ic_cdk::call::<(Account,), (Nat,)>(ledger, "icrc1_balance_of", (account,))
+        .await
+        .map(|(balance,)| balance)
+        .map_err(|e| call_failed(ledger, "icrc1_balance_of", e))
+}
+
+// The factory spends allowances with its default account
+pub fn spender() -> Account {
+    Account::from(ic_cdk::id())
+}
+
+// Allowance `account` gave the factory; an expired one counts as none
+pub async fn allowance(ledger: Principal, account: Account) -> Result<Allowance, FactoryError> {
+    let args = AllowanceArgs { account, spender: spender() };
+    let (mut allowance,): (Allowance,) = ic_cdk::call(ledger, "icrc2_allowance", (args,))
+        .await
+        .map_err(|e| call_failed(ledger, "icrc2_allowance", e))?;
+    if allowance.expires_at.is_some_and(|expires_at| expires_at <= ic_cdk::api::time()) {
+        allowance.allowance = Nat::from(0u32);
+    }
+    Ok(allowance)
+}
+
+// Fail before anything is recorded if a transfer_from of `amount` would be rejected for
+// a missing allowance, so callers get the structured error up front
+pub async fn ensure_allowance(ledger: Principal, from: Account, amount: &Nat) -> Result<(), FactoryError> {
+    let (fee, allowance) = futures::try_join!(fee(ledger), allowance(ledger, from))?;
+    let required = amount.clone() + fee;
+    if allowance.allowance < required {
+        return Err(FactoryError::InsufficientAllowance { required, available: allowance.allowance });
+    }
+    Ok(())
+}
+
+// Move an escrowed amount out of a factory subaccount, paying the ledger fee from it
+pub async fn release(ledger: Principal, from_subaccount: Subaccount, to: Account, amount: Nat, dedup: Dedup) -> Result<Option<BlockIndex>, FactoryError> {
+    let fee = fee(ledger).await?;
+    if amount <= fee {
+        return Ok(None);
+    }
+    transfer(ledger, Some(from_subaccount), to, amount - fee, dedup).await.map(Some)
+}
+
+// Burn talent tokens the holder approved for the factory by moving them to the minting account
+pub async fn burn_from(ledger: Principal, from: Account, amount: Nat, dedup: Dedup) -> Result<BlockIndex, FactoryError> {
+    transfer_from(ledger, from, Account::from(ic_cdk::id()), amount, dedup).await
+}
+
+// The factory is the minting account of every talent ledger, so minting is a plain transfer
+pub async fn mint(ledger: Principal, to: Account, amount: Nat, dedup: Dedup) -> Result<BlockIndex, FactoryError> {
+    transfer(ledger, None, to, amount, dedup).await
+}
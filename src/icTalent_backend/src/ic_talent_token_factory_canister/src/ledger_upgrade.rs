@@ -0,0 +1,325 @@
+use candid::{Encode, Principal};
+use crate::ledger;
+use crate::state_handler::{ensure_role, InFlightGuard, STATE};
+use crate::types::*;
+use crate::wasm_store::ensure_wasm_kind;
+use futures::future::join_all;
+use ic_cdk::api::caller;
+use ic_cdk::api::management_canister::main::{CanisterInstallMode, InstallCodeArgument};
+use std::time::Duration;
+
+const DEFAULT_BATCH_SIZE: u32 = 5;
+const MAX_BATCH_SIZE: u32 = 20;
+const DEFAULT_BATCH_INTERVAL_SECONDS: u64 = 10;
+
+pub const UPGRADE_RUN: &str = "Ledger upgrade run";
+
+fn ensure_no_running_upgrade() -> Result<(), FactoryError> {
+    STATE.with(|state| {
+        let running = state.borrow().ledger_upgrade_runs.values()
+            .find(|run| run.status == UpgradeRunStatus::Running);
+        match running {
+            Some(run) => Err(FactoryError::InProgress { operation: "Ledger upgrade run".to_string(), id: run.id }),
+            None => Ok(()),
+        }
+    })
+}
+
+#[ic_cdk::update]
+pub fn upgrade_talent_ledgers(args: UpgradeLedgersArgs) -> Result<u64, FactoryError> {
+    let admin = caller();
+    ensure_role(admin, Role::SuperAdmin)?;
+    ensure_no_running_upgrade()?;
+
+    let batch_size = args.batch_size.unwrap_or(DEFAULT_BATCH_SIZE);
+    if batch_size == 0 || batch_size > MAX_BATCH_SIZE {
+        return Err(FactoryError::InvalidArgument(format!("Batch size must be between 1 and {}", MAX_BATCH_SIZE)));
+    }
+    let batch_interval_seconds = args.batch_interval_seconds.unwrap_or(DEFAULT_BATCH_INTERVAL_SECONDS);
+
+    let run_id = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        ensure_wasm_kind(&state, &args.wasm_version, WasmKind::Ledger)?;
+        let index_wasm_version = args.index_wasm_version.or_else(|| state.config.get().active_index_wasm_version.clone());
+        if let Some(version) = &index_wasm_version {
+            ensure_wasm_kind(&state, version, WasmKind::Index)?;
+        }
+
+        let canisters = match args.targets {
+            UpgradeTargets::All => state.tokens.keys().collect::<Vec<_>>(),
+            UpgradeTargets::Canisters(canisters) => {
+                if let Some(unknown) = canisters.iter().find(|id| !state.tokens.contains_key(id)) {
+                    return Err(FactoryError::TokenNotFound(*unknown));
+                }
+                canisters
+            }
+        };
+        if canisters.is_empty() {
+            return Err(FactoryError::InvalidArgument("No talent ledgers to upgrade".to_string()));
+        }
+
+        let run_id = state.ledger_upgrade_runs.last_key_value().map(|(id, _)| id + 1).unwrap_or(0);
+        state.ledger_upgrade_runs.insert(run_id, LedgerUpgradeRun {
+            id: run_id,
+            wasm_version: args.wasm_version,
+            index_wasm_version,
+            started_by: admin,
+            started_at: ic_cdk::api::time(),
+            finished_at: None,
+            batch_size,
+            batch_interval_seconds,
+            status: UpgradeRunStatus::Running,
+            canisters: canisters.into_iter().map(|id| (id, LedgerUpgradeStatus::Pending)).collect(),
+        });
+        Ok(run_id)
+    })?;
+
+    schedule_batch(run_id, Duration::ZERO);
+    Ok(run_id)
+}
+
+#[ic_cdk::update]
+pub fn pause_ledger_upgrade(run_id: u64) -> Result<String, FactoryError> {
+    ensure_role(caller(), Role::SuperAdmin)?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let mut run = state.ledger_upgrade_runs.get(&run_id)
+            .ok_or(FactoryError::UpgradeRunNotFound(run_id))?;
+        if run.status != UpgradeRunStatus::Running {
+            return Err(FactoryError::InvalidState(format!("Ledger upgrade run {} is not running", run_id)));
+        }
+        // The batch in flight finishes, the next one is not started
+        run.status = UpgradeRunStatus::Paused;
+        state.ledger_upgrade_runs.insert(run_id, run);
+        Ok(format!("Ledger upgrade run {} paused", run_id))
+    })
+}
+
+#[ic_cdk::update]
+pub fn resume_ledger_upgrade(run_id: u64, retry_failed: bool) -> Result<String, FactoryError> {
+    ensure_role(caller(), Role::SuperAdmin)?;
+    ensure_no_running_upgrade()?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let mut run = state.ledger_upgrade_runs.get(&run_id)
+            .ok_or(FactoryError::UpgradeRunNotFound(run_id))?;
+        ensure_wasm_kind(&state, &run.wasm_version, WasmKind::Ledger)?;
+        if let Some(version) = &run.index_wasm_version {
+            ensure_wasm_kind(&state, version, WasmKind::Index)?;
+        }
+
+        if retry_failed {
+            for (_, status) in run.canisters.iter_mut() {
+                if matches!(status, LedgerUpgradeStatus::Failed { .. }) {
+                    *status = LedgerUpgradeStatus::Pending;
+                }
+            }
+        }
+        if !run.canisters.iter().any(|(_, status)| *status == LedgerUpgradeStatus::Pending) {
+            return Err(FactoryError::InvalidState(format!("Ledger upgrade run {} has nothing left to upgrade", run_id)));
+        }
+
+        run.status = UpgradeRunStatus::Running;
+        run.finished_at = None;
+        state.ledger_upgrade_runs.insert(run_id, run);
+        Ok(())
+    })?;
+
+    // A batch still in flight from before the pause schedules the next one once it settles
+    if !InFlightGuard::is_held(UPGRADE_RUN, run_id) {
+        schedule_batch(run_id, Duration::ZERO);
+    }
+    Ok(format!("Ledger upgrade run {} resumed", run_id))
+}
+
+#[ic_cdk::query]
+pub fn get_ledger_upgrade_run(run_id: u64) -> Result<LedgerUpgradeRun, FactoryError> {
+    ensure_role(caller(), Role::SuperAdmin)?;
+
+    STATE.with(|state| {
+        state.borrow().ledger_upgrade_runs.get(&run_id)
+            .ok_or(FactoryError::UpgradeRunNotFound(run_id))
+    })
+}
+
+#[ic_cdk::query]
+pub fn get_ledger_upgrade_reports() -> Result<Vec<LedgerUpgradeReport>, FactoryError> {
+    ensure_role(caller(), Role::SuperAdmin)?;
+
+    STATE.with(|state| {
+        Ok(state.borrow().ledger_upgrade_runs.values().map(|run| upgrade_report(&run)).collect())
+    })
+}
+
+fn upgrade_report(run: &LedgerUpgradeRun) -> LedgerUpgradeReport {
+    let mut upgraded = 0;
+    let mut pending = 0;
+    let mut failures = Vec::new();
+    for (canister_id, status) in &run.canisters {
+        match status {
+            LedgerUpgradeStatus::Pending => pending += 1,
+            LedgerUpgradeStatus::Upgraded { .. } => upgraded += 1,
+            LedgerUpgradeStatus::Failed { error, .. } => failures.push((*canister_id, error.clone())),
+        }
+    }
+
+    LedgerUpgradeReport {
+        id: run.id,
+        wasm_version: run.wasm_version.clone(),
+        status: run.status.clone(),
+        total: run.canisters.len() as u64,
+        upgraded,
+        pending,
+        failures,
+    }
+}
+
+pub fn reschedule_running_upgrades() {
+    let running = STATE.with(|state| {
+        state.borrow().ledger_upgrade_runs.values()
+            .filter(|run| run.status == UpgradeRunStatus::Running)
+            .map(|run| run.id)
+            .collect::<Vec<_>>()
+    });
+    for run_id in running {
+        schedule_batch(run_id, Duration::ZERO);
+    }
+}
+
+fn schedule_batch(run_id: u64, delay: Duration) {
+    ic_cdk_timers::set_timer(delay, move || ic_cdk::spawn(run_upgrade_batch(run_id)));
+}
+
+async fn run_upgrade_batch(run_id: u64) {
+    // One batch per run at a time, so the same Pending canisters are never upgraded twice
+    let Ok(_guard) = InFlightGuard::acquire(UPGRADE_RUN, run_id) else {
+        return;
+    };
+    let Some(run) = STATE.with(|state| state.borrow().ledger_upgrade_runs.get(&run_id)) else {
+        return;
+    };
+    if run.status != UpgradeRunStatus::Running {
+        return;
+    }
+
+    let batch = run.canisters.iter()
+        .filter(|(_, status)| *status == LedgerUpgradeStatus::Pending)
+        .map(|(canister_id, _)| *canister_id)
+        .take(run.batch_size as usize)
+        .collect::<Vec<_>>();
+
+    let (wasm_module, index_wasm_module, index_canisters) = STATE.with(|state| {
+        let state = state.borrow();
+        let index_canisters = batch.iter()
+            .map(|canister_id| state.tokens.get(canister_id).and_then(|metadata| metadata.index_canister_id))
+            .collect::<Vec<_>>();
+        (
+            state.wasm_modules.get(&run.wasm_version),
+            run.index_wasm_version.as_ref().and_then(|version| state.wasm_modules.get(version)),
+            index_canisters,
+        )
+    });
+    if run.index_wasm_version.is_some() && index_wasm_module.is_none() {
+        ic_cdk::println!("Index WASM of ledger upgrade run {} is missing, index canisters are skipped", run_id);
+    }
+    let results = match wasm_module {
+        Some(wasm_module) => {
+            join_all(batch.iter().zip(index_canisters).map(|(canister_id, index_canister_id)| {
+                let index = index_canister_id.zip(index_wasm_module.clone());
+                upgrade_token(*canister_id, wasm_module.clone(), index)
+            })).await
+        }
+        None => batch.iter()
+            .map(|_| (Err(FactoryError::WasmVersionNotFound(run.wasm_version.clone())), None))
+            .collect(),
+    };
+
+    let keep_going = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let Some(mut run) = state.ledger_upgrade_runs.get(&run_id) else {
+            return false;
+        };
+
+        let now = ic_cdk::api::time();
+        for (canister_id, (ledger_result, index_result)) in batch.iter().zip(results) {
+            if let Some(mut metadata) = state.tokens.get(canister_id) {
+                if ledger_result.is_ok() {
+                    metadata.wasm_version = Some(run.wasm_version.clone());
+                }
+                if let Some(Ok(())) = index_result {
+                    metadata.index_wasm_version = run.index_wasm_version.clone();
+                }
+                state.tokens.insert(*canister_id, metadata);
+            }
+            let status = match (ledger_result, index_result) {
+                (Ok(()), None | Some(Ok(()))) => LedgerUpgradeStatus::Upgraded { at: now },
+                (Err(error), _) => LedgerUpgradeStatus::Failed { at: now, error: error.to_string() },
+                (Ok(()), Some(Err(error))) => LedgerUpgradeStatus::Failed { at: now, error: format!("Ledger upgraded, index failed: {}", error) },
+            };
+            if let Some(entry) = run.canisters.iter_mut().find(|(id, _)| id == canister_id) {
+                entry.1 = status;
+            }
+        }
+
+        let has_pending = run.canisters.iter().any(|(_, status)| *status == LedgerUpgradeStatus::Pending);
+        if !has_pending {
+            run.status = UpgradeRunStatus::Completed;
+            run.finished_at = Some(now);
+        }
+        let keep_going = has_pending && run.status == UpgradeRunStatus::Running;
+        state.ledger_upgrade_runs.insert(run_id, run);
+        keep_going
+    });
+
+    if keep_going {
+        schedule_batch(run_id, Duration::from_secs(run.batch_interval_seconds));
+    }
+}
+
+// Upgrade the ledger, then its index if it has one; the index only follows a successful ledger upgrade
+async fn upgrade_token(canister_id: Principal, wasm_module: Vec<u8>, index: Option<(Principal, Vec<u8>)>) -> (Result<(), FactoryError>, Option<Result<(), FactoryError>>) {
+    let ledger_result = upgrade_ledger(canister_id, wasm_module).await;
+    let index_result = match (&ledger_result, index) {
+        (Ok(()), Some((index_canister_id, index_wasm_module))) => Some(upgrade_index(index_canister_id, index_wasm_module).await),
+        _ => None,
+    };
+    (ledger_result, index_result)
+}
+
+async fn upgrade_index(canister_id: Principal, wasm_module: Vec<u8>) -> Result<(), FactoryError> {
+    let upgrade_arg = Encode!(&None::<IndexArg>)
+        .map_err(|e| FactoryError::InvalidArgument(format!("Serialization failed: {:?}", e)))?;
+
+    let install_config = InstallCodeArgument {
+        mode: CanisterInstallMode::Upgrade(None),
+        canister_id,
+        wasm_module,
+        arg: upgrade_arg,
+    };
+
+    ic_cdk::api::call::call::<_, ()>(
+        Principal::management_canister(),
+        "install_code",
+        (install_config,)
+    ).await.map_err(|e| ledger::call_failed(Principal::management_canister(), "install_code", e))
+}
+
+async fn upgrade_ledger(canister_id: Principal, wasm_module: Vec<u8>) -> Result<(), FactoryError> {
+    let upgrade_arg = Encode!(&LedgerArg::Upgrade(None))
+        .map_err(|e| FactoryError::InvalidArgument(format!("Serialization failed: {:?}", e)))?;
+
+    let install_config = InstallCodeArgument {
+        mode: CanisterInstallMode::Upgrade(None),
+        canister_id,
+        wasm_module,
+        arg: upgrade_arg,
+    };
+
+    ic_cdk::api::call::call::<_, ()>(
+        Principal::management_canister(),
+        "install_code",
+        (install_config,)
+    ).await.map_err(|e| ledger::call_failed(Principal::management_canister(), "install_code", e))
+}
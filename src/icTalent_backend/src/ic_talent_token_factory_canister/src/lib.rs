@@ -3,8 +3,25 @@ mod state_handler;
 mod api_update;
 mod api_query;
 mod token_pool;
-use icrc_ledger_types::icrc1::transfer::BlockIndex;
+mod wasm_store;
+mod ledger_upgrade;
+mod ledger;
+mod token_creation;
+mod purchases;
+mod pricing;
+mod redemptions;
+mod access_control;
+mod faucet;
+mod faucet_eligibility;
+mod payments;
+mod idempotency;
+mod quotes;
+mod trades;
+mod market;
+mod catalog;
+mod portfolio;
 use candid::{Nat, Principal};
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
 use crate::types::*;
 
 ic_cdk::export_candid!();
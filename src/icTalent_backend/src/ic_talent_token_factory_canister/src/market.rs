@@ -0,0 +1,212 @@
+use candid::{Nat, Principal};
+use crate::pricing;
+use crate::state_handler::{CandleMap, State, STATE};
+use crate::trades;
+use crate::types::*;
+use std::ops::Bound;
+
+const HOUR_NANOS: u64 = 3_600 * 1_000_000_000;
+const DAY_NANOS: u64 = 24 * HOUR_NANOS;
+const WEEK_NANOS: u64 = 7 * DAY_NANOS;
+// The epoch fell on a Thursday; shift weekly buckets to start on Mondays
+const WEEK_OFFSET_NANOS: u64 = 3 * DAY_NANOS;
+
+const DEFAULT_CANDLE_LIMIT: u32 = 200;
+const MAX_CANDLE_LIMIT: u32 = 1_000;
+
+const INTERVALS: [CandleInterval; 3] = [CandleInterval::Hour, CandleInterval::Day, CandleInterval::Week];
+
+fn bucket_start(interval: CandleInterval, timestamp: u64) -> u64 {
+    match interval {
+        CandleInterval::Hour => timestamp - timestamp % HOUR_NANOS,
+        CandleInterval::Day => timestamp - timestamp % DAY_NANOS,
+        CandleInterval::Week => {
+            let shifted = timestamp + WEEK_OFFSET_NANOS;
+            (shifted - shifted % WEEK_NANOS).saturating_sub(WEEK_OFFSET_NANOS)
+        }
+    }
+}
+
+fn candles(state: &State, interval: CandleInterval) -> &CandleMap {
+    match interval {
+        CandleInterval::Hour => &state.hourly_candles,
+        CandleInterval::Day => &state.daily_candles,
+        CandleInterval::Week => &state.weekly_candles,
+    }
+}
+
+fn candles_mut(state: &mut State, interval: CandleInterval) -> &mut CandleMap {
+    match interval {
+        CandleInterval::Hour => &mut state.hourly_candles,
+        CandleInterval::Day => &mut state.daily_candles,
+        CandleInterval::Week => &mut state.weekly_candles,
+    }
+}
+
+// Add a purchase or sale of `quantity` talent base units for `amount` platform base units
+// to every interval. Fills must come in time order for open and close to be right.
+pub fn record_fill(state: &mut State, token: Principal, timestamp: u64, quantity: &Nat, amount: &Nat) {
+    let Some(price) = pricing::unit_price(amount, quantity, trades::token_decimals(state, token)) else {
+        return;
+    };
+
+    for interval in INTERVALS {
+        let start = bucket_start(interval, timestamp);
+        let map = candles_mut(state, interval);
+        let candle = match map.get(&(token, start)) {
+            Some(mut candle) => {
+                if price > candle.high {
+                    candle.high = price.clone();
+                }
+                if price < candle.low {
+                    candle.low = price.clone();
+                }
+                candle.close = price.clone();
+                candle.volume += quantity.clone();
+                candle.quote_volume += amount.clone();
+                candle.trades += 1;
+                candle
+            }
+            None => Candle {
+                start,
+                open: price.clone(),
+                high: price.clone(),
+                low: price.clone(),
+                close: price.clone(),
+                volume: quantity.clone(),
+                quote_volume: amount.clone(),
+                trades: 1,
+            },
+        };
+        map.insert((token, start), candle);
+    }
+}
+
+// The most recent candles starting in [from, to), oldest first. `to` defaults to now.
+#[ic_cdk::query]
+pub fn get_candles(token: Principal, interval: CandleInterval, from: Option<u64>, to: Option<u64>, limit: Option<u32>) -> Result<Vec<Candle>, FactoryError> {
+    let limit = match limit.unwrap_or(DEFAULT_CANDLE_LIMIT) {
+        0 => return Err(FactoryError::InvalidArgument("Limit must be greater than zero".to_string())),
+        limit if limit > MAX_CANDLE_LIMIT => return Err(FactoryError::InvalidArgument(format!("Limit cannot exceed {}", MAX_CANDLE_LIMIT))),
+        limit => limit as usize,
+    };
+
+    STATE.with(|state| {
+        let state = state.borrow();
+        if !state.tokens.contains_key(&token) {
+            return Err(FactoryError::TokenNotFound(token));
+        }
+
+        let from = from.unwrap_or(0);
+        let to = to.unwrap_or_else(|| ic_cdk::api::time().saturating_add(1));
+        if from >= to {
+            return Err(FactoryError::InvalidArgument("The time range is empty".to_string()));
+        }
+
+        let mut result: Vec<Candle> = candles(&state, interval)
+            .range((Bound::Included((token, from)), Bound::Excluded((token, to))))
+            .rev()
+            .take(limit)
+            .map(|(_, candle)| candle)
+            .collect();
+        result.reverse();
+        Ok(result)
+    })
+}
+
+// Price change and volume over the last 24 hours, counted in whole hourly candles
+#[ic_cdk::query]
+pub fn get_market_summary(token: Principal) -> Result<MarketSummary, FactoryError> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        if !state.tokens.contains_key(&token) {
+            return Err(FactoryError::TokenNotFound(token));
+        }
+        Ok(market_summary(&state, token, ic_cdk::api::time()))
+    })
+}
+
+pub fn market_summary(state: &State, token: Principal, now: u64) -> MarketSummary {
+    let hourly = &state.hourly_candles;
+    let window_start = bucket_start(CandleInterval::Hour, now).saturating_sub(23 * HOUR_NANOS);
+
+    let last_price = hourly.range((token, 0)..=(token, u64::MAX)).next_back().map(|(_, candle)| candle.close);
+    let price_before = hourly.range((token, 0)..(token, window_start)).next_back().map(|(_, candle)| candle.close);
+
+    let mut summary = MarketSummary {
+        token,
+        last_price,
+        price_24h_ago: price_before,
+        change_24h_bps: None,
+        high_24h: None,
+        low_24h: None,
+        volume_24h: Nat::from(0u32),
+        quote_volume_24h: Nat::from(0u32),
+        trades_24h: 0,
+    };
+    for (_, candle) in hourly.range((token, window_start)..=(token, u64::MAX)) {
+        if summary.price_24h_ago.is_none() {
+            summary.price_24h_ago = Some(candle.open.clone());
+        }
+        if summary.high_24h.as_ref().is_none_or(|high| candle.high > *high) {
+            summary.high_24h = Some(candle.high.clone());
+        }
+        if summary.low_24h.as_ref().is_none_or(|low| candle.low < *low) {
+            summary.low_24h = Some(candle.low.clone());
+        }
+        summary.volume_24h += candle.volume;
+        summary.quote_volume_24h += candle.quote_volume;
+        summary.trades_24h += candle.trades;
+    }
+
+    if let (Some(last), Some(before)) = (&summary.last_price, &summary.price_24h_ago) {
+        summary.change_24h_bps = change_bps(before, last);
+    }
+    summary
+}
+
+fn change_bps(before: &Nat, after: &Nat) -> Option<i64> {
+    if *before == 0u32 {
+        return None;
+    }
+    let (difference, negative) = if after >= before {
+        (after.clone() - before.clone(), false)
+    } else {
+        (before.clone() - after.clone(), true)
+    };
+    let bps = i64::try_from(&(difference * 10_000u32 / before.clone()).0).unwrap_or(i64::MAX);
+    Some(if negative { -bps } else { bps })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND_NANOS: u64 = 1_000_000_000;
+    // Monday 2024-01-01 00:00:00 UTC
+    const MONDAY: u64 = 1_704_067_200 * SECOND_NANOS;
+
+    #[test]
+    fn hour_and_day_buckets_round_down() {
+        let timestamp = MONDAY + 5 * HOUR_NANOS + 42 * SECOND_NANOS;
+        assert_eq!(bucket_start(CandleInterval::Hour, timestamp), MONDAY + 5 * HOUR_NANOS);
+        assert_eq!(bucket_start(CandleInterval::Day, timestamp), MONDAY);
+        assert_eq!(bucket_start(CandleInterval::Hour, MONDAY), MONDAY);
+    }
+
+    #[test]
+    fn weeks_start_on_monday() {
+        assert_eq!(bucket_start(CandleInterval::Week, MONDAY), MONDAY);
+        // Sunday night still belongs to the week before
+        assert_eq!(bucket_start(CandleInterval::Week, MONDAY + WEEK_NANOS - 1), MONDAY);
+        assert_eq!(bucket_start(CandleInterval::Week, MONDAY + WEEK_NANOS), MONDAY + WEEK_NANOS);
+        assert_eq!(bucket_start(CandleInterval::Week, MONDAY - 1), MONDAY - WEEK_NANOS);
+    }
+
+    #[test]
+    fn first_week_saturates_at_the_epoch() {
+        // The epoch was a Thursday, its Monday lies before zero
+        assert_eq!(bucket_start(CandleInterval::Week, 0), 0);
+        assert_eq!(bucket_start(CandleInterval::Week, 4 * DAY_NANOS), 4 * DAY_NANOS);
+    }
+}
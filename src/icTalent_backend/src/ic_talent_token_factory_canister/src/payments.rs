@@ -0,0 +1,165 @@
+use candid::{Nat, Principal};
+use crate::idempotency;
+use crate::ledger;
+use crate::state_handler::{ensure_role, InFlightGuard, State, STATE};
+use crate::types::*;
+use ic_cdk::api::caller;
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::BlockIndex;
+
+const PAYMENT: &str = "Payment";
+const MAX_MEMO_BYTES: usize = 32;
+const MAX_PURPOSE_LENGTH: usize = 32;
+
+// Move funds `from` has approved for the factory. Internal only: the allowance is meant for
+// purchases, so it is spent on the owner's behalf only through an explicit pay call.
+async fn transfer_tokens(from: Account, to: Account, amount: Nat, dedup: ledger::Dedup) -> Result<BlockIndex, FactoryError> {
+    let token_canister = STATE.with(|state| state.borrow().token_canister_id());
+    ledger::transfer_from(token_canister, from, to, amount, dedup).await
+}
+
+fn validate(args: &PaymentArgs) -> Result<(), FactoryError> {
+    if args.to == Principal::anonymous() {
+        return Err(FactoryError::InvalidArgument("Payments cannot go to the anonymous principal".to_string()));
+    }
+    if args.amount == 0u32 {
+        return Err(FactoryError::InvalidArgument("Amount must be greater than zero".to_string()));
+    }
+    if args.memo.as_ref().is_some_and(|memo| memo.0.len() > MAX_MEMO_BYTES) {
+        return Err(FactoryError::InvalidArgument(format!("Memo cannot exceed {} bytes", MAX_MEMO_BYTES)));
+    }
+    if args.purpose.trim().is_empty() || args.purpose.len() > MAX_PURPOSE_LENGTH {
+        return Err(FactoryError::InvalidArgument(format!("Purpose must be 1 to {} characters", MAX_PURPOSE_LENGTH)));
+    }
+    idempotency::validate_key(&args.idempotency_key)
+}
+
+fn payments_of(state: &State, payer: Principal) -> Vec<Payment> {
+    state.payments_by_payer
+        .range((payer, 0)..=(payer, u64::MAX))
+        .filter_map(|((_, id), _)| state.payments.get(&id))
+        .collect()
+}
+
+fn save_payment(mut payment: Payment) {
+    payment.updated_at = ic_cdk::api::time();
+    STATE.with(|state| {
+        state.borrow_mut().payments.insert(payment.id, payment);
+    });
+}
+
+// The payment to run for this call: a new one, or a failed one with the same key again
+fn open_payment(payer: Principal, args: PaymentArgs) -> Result<Payment, FactoryError> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let existing = payments_of(&state, payer).into_iter()
+            .find(|payment| payment.idempotency_key == args.idempotency_key);
+
+        if let Some(payment) = existing {
+            if payment.to != args.to
+                || payment.to_subaccount != args.to_subaccount
+                || payment.from_subaccount != args.from_subaccount
+                || payment.amount != args.amount
+                || payment.memo != args.memo
+                || payment.purpose != args.purpose
+            {
+                return Err(FactoryError::InvalidArgument(format!(
+                    "Idempotency key {} was already used for a different payment",
+                    args.idempotency_key
+                )));
+            }
+            return match payment.status {
+                PaymentStatus::Pending => Err(FactoryError::InProgress { operation: PAYMENT.to_string(), id: payment.id }),
+                PaymentStatus::Completed | PaymentStatus::Failed => Ok(payment),
+            };
+        }
+
+        let id = state.payments.last_key_value().map(|(id, _)| id + 1).unwrap_or(0);
+        let now = ic_cdk::api::time();
+        let payment = Payment {
+            id,
+            payer,
+            to: args.to,
+            amount: args.amount,
+            memo: args.memo,
+            purpose: args.purpose,
+            idempotency_key: args.idempotency_key,
+            status: PaymentStatus::Pending,
+            block: None,
+            last_error: None,
+            created_at: now,
+            updated_at: now,
+            from_subaccount: args.from_subaccount,
+            to_subaccount: args.to_subaccount,
+        };
+        state.payments.insert(id, payment.clone());
+        state.payments_by_payer.insert((payer, id), ());
+        Ok(payment)
+    })
+}
+
+// Pay `to` out of the allowance the caller gave the factory on the platform ledger.
+// Every call is logged; retrying with the same idempotency key never pays twice and
+// retries the transfer only if the first attempt failed.
+#[ic_cdk::update]
+pub async fn pay(args: PaymentArgs) -> Result<Payment, FactoryError> {
+    let payer = caller();
+    if payer == Principal::anonymous() {
+        return Err(FactoryError::AnonymousCaller);
+    }
+    validate(&args)?;
+
+    let mut payment = open_payment(payer, args)?;
+    if payment.status == PaymentStatus::Completed {
+        return Ok(payment);
+    }
+
+    let _guard = InFlightGuard::acquire(PAYMENT, payment.id)?;
+    payment.status = PaymentStatus::Pending;
+    payment.last_error = None;
+    save_payment(payment.clone());
+
+    let from = Account { owner: payer, subaccount: payment.from_subaccount };
+    let to = Account { owner: payment.to, subaccount: payment.to_subaccount };
+    // A retry of a failed payment reuses the first attempt's memo and time, so the ledger
+    // reports it as a duplicate if that attempt went through after all
+    let mut dedup = ledger::Dedup::new(&format!("payment/{}", payment.id), payment.created_at);
+    if let Some(memo) = payment.memo.clone() {
+        dedup.memo = memo;
+    }
+    let result = transfer_tokens(from, to, payment.amount.clone(), dedup).await;
+    match &result {
+        Ok(block) => {
+            payment.status = PaymentStatus::Completed;
+            payment.block = Some(block.clone());
+        }
+        Err(e) => {
+            payment.status = PaymentStatus::Failed;
+            payment.last_error = Some(e.to_string());
+        }
+    }
+    save_payment(payment.clone());
+    result.map(|_| payment)
+}
+
+#[ic_cdk::query]
+pub fn get_payment(payment_id: u64) -> Result<Payment, FactoryError> {
+    let payment = STATE.with(|state| state.borrow().payments.get(&payment_id))
+        .ok_or(FactoryError::PaymentNotFound(payment_id))?;
+    if payment.payer != caller() {
+        ensure_role(caller(), Role::Treasurer)?;
+    }
+    Ok(payment)
+}
+
+#[ic_cdk::query]
+pub fn get_my_payments() -> Result<Vec<Payment>, FactoryError> {
+    let caller = caller();
+    STATE.with(|state| Ok(payments_of(&state.borrow(), caller)))
+}
+
+#[ic_cdk::query]
+pub fn get_payments() -> Result<Vec<Payment>, FactoryError> {
+    ensure_role(caller(), Role::Treasurer)?;
+    STATE.with(|state| Ok(state.borrow().payments.values().collect()))
+}
@@ -0,0 +1,238 @@
+use candid::{Nat, Principal};
+use crate::ledger;
+use crate::state_handler::{State, STATE};
+use crate::trades;
+use crate::types::*;
+use futures::stream::{self, StreamExt};
+use ic_cdk::api::caller;
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+
+// Ledger calls in flight at once per portfolio read
+const BALANCE_FAN_OUT: usize = 10;
+const MAX_SUBACCOUNTS: usize = 10;
+const MAX_TRACKED_TOKENS: usize = 100;
+// Ledger calls one portfolio read may make, holdings and discovery probes together
+const MAX_BALANCE_READS: usize = 500;
+// Balance reads an update call spends on catalog tokens the factory has no record of the
+// caller holding, e.g. ones received by transfer. The next call continues the sweep.
+const DISCOVERY_READS: usize = 200;
+// Balances kept in the heap cache; the oldest reads are dropped first
+const MAX_CACHED_BALANCES: usize = 10_000;
+
+// Last non-zero balance read per (token, account) and when; heap only, so it starts empty after an upgrade
+struct BalanceCache {
+    balances: BTreeMap<(Principal, Account), (Nat, u64)>,
+    by_age: BTreeSet<(u64, Principal, Account)>,
+}
+
+impl BalanceCache {
+    fn get(&self, token: Principal, account: Account) -> Option<(Nat, u64)> {
+        self.balances.get(&(token, account)).cloned()
+    }
+
+    // A zero balance only drops the old entry, so empty subaccounts take no room
+    fn record(&mut self, token: Principal, account: Account, balance: &Nat, fetched_at: u64) {
+        if let Some((_, old_fetched_at)) = self.balances.remove(&(token, account)) {
+            self.by_age.remove(&(old_fetched_at, token, account));
+        }
+        if *balance == 0u32 {
+            return;
+        }
+        if self.balances.len() >= MAX_CACHED_BALANCES {
+            if let Some((_, oldest_token, oldest_account)) = self.by_age.pop_first() {
+                self.balances.remove(&(oldest_token, oldest_account));
+            }
+        }
+        self.balances.insert((token, account), (balance.clone(), fetched_at));
+        self.by_age.insert((fetched_at, token, account));
+    }
+}
+
+thread_local! {
+    static BALANCE_CACHE: RefCell<BalanceCache> = const {
+        RefCell::new(BalanceCache { balances: BTreeMap::new(), by_age: BTreeSet::new() })
+    };
+    // Last catalog token each owner's discovery sweep looked at; heap only, a lost one restarts the sweep
+    static DISCOVERY_CURSOR: RefCell<BTreeMap<Principal, Principal>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+// Tokens the owner bought, was minted, created, tracks by hand or was found holding by a discovery sweep
+fn holdings(state: &State, owner: Principal) -> BTreeSet<Principal> {
+    let mut tokens: BTreeSet<Principal> = trades::tokens_bought_by(state, owner).into_iter().collect();
+    tokens.extend(state.token_holdings
+        .range((owner, Principal::management_canister())..)
+        .take_while(|((holder, _), _)| *holder == owner)
+        .map(|((_, token), _)| token));
+    tokens.extend(state.talent_token_map.get(&owner));
+    tokens
+}
+
+// Next catalog tokens outside `known` to probe for a balance, resuming after the last sweep.
+// Reaching the end of the catalog restarts the sweep on the following call.
+fn discovery_batch(state: &State, owner: Principal, known: &BTreeSet<Principal>, count: usize) -> Vec<Principal> {
+    let after = DISCOVERY_CURSOR.with(|cursor| cursor.borrow().get(&owner).copied());
+    let lower = after.map_or(Bound::Unbounded, Bound::Excluded);
+    let batch: Vec<Principal> = state.tokens
+        .range((lower, Bound::Unbounded))
+        .map(|(token, _)| token)
+        .filter(|token| !known.contains(token))
+        .take(count)
+        .collect();
+    DISCOVERY_CURSOR.with(|cursor| {
+        let mut cursor = cursor.borrow_mut();
+        match batch.last() {
+            Some(last) if batch.len() == count => cursor.insert(owner, *last),
+            _ => cursor.remove(&owner),
+        }
+    });
+    batch
+}
+
+// Tokens in the owner's holdings index, whether tracked by hand, minted or discovered
+fn tracked_count(state: &State, owner: Principal) -> usize {
+    state.token_holdings
+        .range((owner, Principal::management_canister())..)
+        .take_while(|((holder, _), _)| *holder == owner)
+        .count()
+}
+
+fn accounts_of(owner: Principal, subaccounts: Option<Vec<Subaccount>>) -> Result<Vec<Account>, FactoryError> {
+    match subaccounts {
+        Some(subaccounts) if subaccounts.len() > MAX_SUBACCOUNTS => {
+            Err(FactoryError::InvalidArgument(format!("At most {} subaccounts can be read at once", MAX_SUBACCOUNTS)))
+        }
+        Some(subaccounts) => Ok(subaccounts.into_iter()
+            .map(|subaccount| Account { owner, subaccount: Some(subaccount) })
+            .collect()),
+        None => Ok(vec![Account::from(owner)]),
+    }
+}
+
+// Read every holding of every account with bounded fan-out. A ledger that fails is reported
+// on its entry, with the last cached balance if there is one. With `discover` the reads also
+// cover the next discovery batch; probed tokens only show up, and are recorded as holdings,
+// if some account holds them, and a failed probe is left for a later sweep. Discovery stops
+// once the holdings index is full, like track_token.
+async fn read_balances(owner: Principal, subaccounts: Option<Vec<Subaccount>>, discover: bool) -> Result<Vec<TokenBalance>, FactoryError> {
+    if owner == Principal::anonymous() {
+        return Err(FactoryError::AnonymousCaller);
+    }
+    let accounts = accounts_of(owner, subaccounts)?;
+    let (tokens, probes, room) = STATE.with(|state| {
+        let state = state.borrow();
+        let tokens = holdings(&state, owner);
+        let holding_reads = tokens.len() * accounts.len();
+        if holding_reads > MAX_BALANCE_READS {
+            return Err(FactoryError::InvalidArgument(format!("At most {} balances can be read at once; pass fewer subaccounts", MAX_BALANCE_READS)));
+        }
+        let room = MAX_TRACKED_TOKENS.saturating_sub(tracked_count(&state, owner));
+        let probe_reads = DISCOVERY_READS.min(MAX_BALANCE_READS - holding_reads);
+        let probes: BTreeSet<Principal> = if discover && room > 0 && !accounts.is_empty() {
+            discovery_batch(&state, owner, &tokens, probe_reads / accounts.len()).into_iter().collect()
+        } else {
+            BTreeSet::new()
+        };
+        Ok((tokens, probes, room))
+    })?;
+    let reads: Vec<(Principal, Account, bool)> = tokens.into_iter()
+        .map(|token| (token, false))
+        .chain(probes.iter().map(|token| (*token, true)))
+        .flat_map(|(token, probe)| accounts.iter().map(move |account| (token, *account, probe)))
+        .collect();
+
+    let balances: Vec<TokenBalance> = stream::iter(reads)
+        .map(|(token, account, probe)| async move {
+            let result = ledger::balance_of(token, account).await;
+            BALANCE_CACHE.with(|cache| {
+                let mut cache = cache.borrow_mut();
+                match result {
+                    Ok(balance) if probe && balance == 0u32 => None,
+                    Ok(balance) => {
+                        let fetched_at = ic_cdk::api::time();
+                        cache.record(token, account, &balance, fetched_at);
+                        Some(TokenBalance { token, subaccount: account.subaccount, balance: Some(balance), fetched_at: Some(fetched_at), error: None })
+                    }
+                    Err(_) if probe => None,
+                    Err(e) => {
+                        let cached = cache.get(token, account);
+                        Some(TokenBalance {
+                            token,
+                            subaccount: account.subaccount,
+                            balance: cached.as_ref().map(|(balance, _)| balance.clone()),
+                            fetched_at: cached.map(|(_, fetched_at)| fetched_at),
+                            error: Some(e.to_string()),
+                        })
+                    }
+                }
+            })
+        })
+        .buffered(BALANCE_FAN_OUT)
+        .filter_map(|balance| async move { balance })
+        .collect()
+        .await;
+
+    let discovered: BTreeSet<Principal> = balances.iter()
+        .map(|balance| balance.token)
+        .filter(|token| probes.contains(token))
+        .collect();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        for token in discovered.into_iter().take(room) {
+            state.token_holdings.insert((owner, token), ());
+        }
+    });
+    Ok(balances)
+}
+
+// Balances of all the caller's holdings in the default account or in each of the given
+// subaccounts. Successful reads refresh the balance cache, and each call also sweeps part
+// of the catalog for tokens the caller received by transfer.
+#[ic_cdk::update]
+pub async fn get_all_token_balances(subaccounts: Option<Vec<Subaccount>>) -> Result<Vec<TokenBalance>, FactoryError> {
+    read_balances(caller(), subaccounts, true).await
+}
+
+// Same as get_all_token_balances as a composite query. It answers faster but cannot update
+// the cache or sweep the catalog, so it only lists tokens already known as holdings and
+// failed reads fall back to what the last update call saw.
+#[ic_cdk::query(composite = true)]
+pub async fn query_all_token_balances(subaccounts: Option<Vec<Subaccount>>) -> Result<Vec<TokenBalance>, FactoryError> {
+    read_balances(caller(), subaccounts, false).await
+}
+
+// Add a token to the caller's portfolio right away instead of waiting for a discovery sweep to find it
+#[ic_cdk::update]
+pub fn track_token(token: Principal) -> Result<(), FactoryError> {
+    let owner = caller();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if !state.tokens.contains_key(&token) {
+            return Err(FactoryError::TokenNotFound(token));
+        }
+        if tracked_count(&state, owner) >= MAX_TRACKED_TOKENS && !state.token_holdings.contains_key(&(owner, token)) {
+            return Err(FactoryError::InvalidArgument(format!("A portfolio can hold at most {} tokens", MAX_TRACKED_TOKENS)));
+        }
+        state.token_holdings.insert((owner, token), ());
+        Ok(())
+    })
+}
+
+// Stop listing a token in the caller's portfolio. Tokens the caller bought stay listed, and
+// a later sweep lists the token again if the caller still holds it.
+#[ic_cdk::update]
+pub fn untrack_token(token: Principal) -> Result<(), FactoryError> {
+    let owner = caller();
+    STATE.with(|state| {
+        state.borrow_mut().token_holdings.remove(&(owner, token));
+    });
+    Ok(())
+}
+
+#[ic_cdk::query]
+pub fn get_portfolio_tokens() -> Result<Vec<Principal>, FactoryError> {
+    let owner = caller();
+    STATE.with(|state| Ok(holdings(&state.borrow(), owner).into_iter().collect()))
+}
@@ -0,0 +1,338 @@
+use candid::{Nat, Principal};
+use crate::catalog;
+use crate::ledger;
+use crate::state_handler::STATE;
+use crate::types::*;
+
+// Growth of the exponential curve is given in basis points per token
+const BPS_DENOMINATOR: u32 = 10_000;
+const MAX_GROWTH_BPS: u32 = 10_000;
+// Fixed-point precision of the exponential curve: decimal digits of the scale and binary
+// digits of the fractional part of the exponent
+const GROWTH_SCALE_DECIMALS: u8 = 18;
+const FRACTION_BITS: u32 = 64;
+
+pub fn to_u128(value: &Nat) -> Result<u128, FactoryError> {
+    u128::try_from(&value.0).map_err(|_| FactoryError::InvalidArgument(format!("Amount {} is too large", value)))
+}
+
+pub fn checked_sub(minuend: &Nat, subtrahend: &Nat) -> Result<Nat, FactoryError> {
+    if minuend < subtrahend {
+        return Err(FactoryError::InvalidState(format!("Cannot subtract {} from {}", subtrahend, minuend)));
+    }
+    Ok(minuend.clone() - subtrahend.clone())
+}
+
+// Base units in one whole token of a ledger with these decimals
+pub fn unit(decimals: u8) -> Nat {
+    (0..decimals).fold(Nat::from(1u32), |unit, _| unit * 10u32)
+}
+
+// Platform base units per whole token paid for `quantity` talent base units
+pub fn unit_price(amount: &Nat, quantity: &Nat, token_decimals: u8) -> Option<Nat> {
+    if *quantity == 0u32 {
+        return None;
+    }
+    Some(amount.clone() * unit(token_decimals) / quantity.clone())
+}
+
+fn div_ceil(numerator: Nat, denominator: &Nat) -> Nat {
+    (numerator + denominator.clone() - 1u32) / denominator.clone()
+}
+
+fn out_of_range() -> FactoryError {
+    FactoryError::InvalidArgument("Price is out of range".to_string())
+}
+
+// (1 + growth_bps / 10_000) ^ (exponent / unit), scaled by 10^GROWTH_SCALE_DECIMALS and
+// rounded down at every step. The whole part of the exponent is raised by squaring, the
+// fraction by multiplying in repeated square roots of the ratio, one per binary digit.
+fn growth_factor(growth_bps: u32, exponent: &Nat, unit: &Nat) -> Result<Nat, FactoryError> {
+    let scale = self::unit(GROWTH_SCALE_DECIMALS);
+    let limit = Nat::from(u128::MAX) * scale.clone();
+    let multiply = |a: &Nat, b: &Nat| a.clone() * b.clone() / scale.clone();
+    let ratio = scale.clone() * (BPS_DENOMINATOR + growth_bps) / BPS_DENOMINATOR;
+
+    let mut factor = scale.clone();
+    let mut whole = exponent.clone() / unit.clone();
+    let mut power = ratio.clone();
+    while whole > 0u32 {
+        if whole.clone() % 2u32 == 1u32 {
+            factor = multiply(&factor, &power);
+            if factor > limit {
+                return Err(out_of_range());
+            }
+        }
+        whole /= 2u32;
+        if whole > 0u32 {
+            power = multiply(&power, &power);
+            // Any further bit would push the factor past the limit
+            if power > limit {
+                return Err(out_of_range());
+            }
+        }
+    }
+
+    let mut fraction = exponent.clone() % unit.clone();
+    let mut root = ratio;
+    for _ in 0..FRACTION_BITS {
+        if fraction == 0u32 {
+            break;
+        }
+        root = Nat((root * scale.clone()).0.sqrt());
+        fraction *= 2u32;
+        if fraction >= *unit {
+            fraction -= unit.clone();
+            factor = multiply(&factor, &root);
+        }
+    }
+    Ok(factor)
+}
+
+pub fn validate_pricing(pricing: &PricingModel) -> Result<(), FactoryError> {
+    match pricing {
+        PricingModel::Fixed { price } if *price == 0u32 => {
+            Err(FactoryError::InvalidArgument("Price must be greater than zero".to_string()))
+        }
+        PricingModel::Linear { base_price, slope } if *base_price == 0u32 && *slope == 0u32 => {
+            Err(FactoryError::InvalidArgument("Linear curve needs a base price or a slope".to_string()))
+        }
+        PricingModel::Exponential { base_price, .. } if *base_price == 0u32 => {
+            Err(FactoryError::InvalidArgument("Exponential curve needs a base price".to_string()))
+        }
+        PricingModel::Exponential { growth_bps, .. } if *growth_bps == 0 || *growth_bps > MAX_GROWTH_BPS => {
+            Err(FactoryError::InvalidArgument(format!("Growth must be between 1 and {} basis points", MAX_GROWTH_BPS)))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Prices are platform base units per whole talent token, while supply and quantity are
+// talent base units, so `token_decimals` converts between the two
+
+// Price of the next whole token once `supply` base units are in circulation
+pub fn spot_price(pricing: &PricingModel, supply: &Nat, token_decimals: u8) -> Result<Nat, FactoryError> {
+    let unit = unit(token_decimals);
+    match pricing {
+        PricingModel::Fixed { price } => Ok(price.clone()),
+        PricingModel::Linear { base_price, slope } => Ok(base_price.clone() + slope.clone() * supply.clone() / unit),
+        PricingModel::Exponential { base_price, growth_bps } => {
+            let factor = growth_factor(*growth_bps, supply, &unit)?;
+            Ok(div_ceil(base_price.clone() * factor, &self::unit(GROWTH_SCALE_DECIMALS)))
+        }
+    }
+}
+
+// Exact cost, rounded up, of buying `quantity` base units starting at `supply`, i.e. the
+// sum of the price of every base unit from supply to supply + quantity - 1
+pub fn purchase_cost(pricing: &PricingModel, supply: &Nat, quantity: &Nat, token_decimals: u8) -> Result<Nat, FactoryError> {
+    if *quantity == 0u32 {
+        return Err(FactoryError::InvalidArgument("Quantity must be greater than zero".to_string()));
+    }
+    let unit = unit(token_decimals);
+
+    match pricing {
+        PricingModel::Fixed { price } => Ok(div_ceil(price.clone() * quantity.clone(), &unit)),
+        PricingModel::Linear { base_price, slope } => {
+            // (n * base * u + slope * (n * s + n * (n - 1) / 2)) / u^2
+            let n = quantity.clone();
+            let steps = n.clone() * supply.clone() + n.clone() * (n.clone() - 1u32) / 2u32;
+            let scaled = n * base_price.clone() * unit.clone() + slope.clone() * steps;
+            Ok(div_ceil(scaled, &(unit.clone() * unit)))
+        }
+        PricingModel::Exponential { base_price, growth_bps } => {
+            // base * r^s * (r^n - 1) / (r - 1), with s and n in whole tokens and r - 1 = growth_bps / 10_000
+            let start = growth_factor(*growth_bps, supply, &unit)?;
+            let end = growth_factor(*growth_bps, &(supply.clone() + quantity.clone()), &unit)?;
+            let scaled = base_price.clone() * (end - start) * BPS_DENOMINATOR;
+            Ok(div_ceil(scaled, &(self::unit(GROWTH_SCALE_DECIMALS) * *growth_bps)))
+        }
+    }
+}
+
+// Pricing of a talent token and its decimals, read from the ledgers once and cached
+pub async fn pricing_of(token: Principal, metadata: &TokenMetadata) -> Result<(PricingModel, u8), FactoryError> {
+    let platform = STATE.with(|state| state.borrow().token_canister_id());
+    let (platform_decimals, token_decimals) = futures::try_join!(ledger::decimals(platform), ledger::decimals(token))?;
+    Ok((metadata.pricing_model(platform_decimals), token_decimals))
+}
+
+// Current cost in platform base units of buying `quantity` base units of a talent token
+pub async fn current_purchase_cost(token: Principal, metadata: &TokenMetadata, quantity: &Nat) -> Result<Nat, FactoryError> {
+    let (pricing, token_decimals) = pricing_of(token, metadata).await?;
+    let supply = circulating_supply(token).await?;
+    purchase_cost(&pricing, &supply, quantity, token_decimals)
+}
+
+// Same as pricing_of for queries, which cannot call the ledgers
+pub fn cached_pricing_of(token: Principal, metadata: &TokenMetadata) -> Result<(PricingModel, u8), FactoryError> {
+    let platform = STATE.with(|state| state.borrow().token_canister_id());
+    let platform_decimals = ledger::cached_decimals(platform)
+        .ok_or_else(|| FactoryError::InvalidState("Decimals of the platform ledger are not known yet".to_string()))?;
+    let token_decimals = ledger::cached_decimals(token).unwrap_or(metadata.decimals);
+    Ok((metadata.pricing_model(platform_decimals), token_decimals))
+}
+
+// Read the platform ledger's decimals in the background so queries can price right away
+pub fn prime_platform_decimals() {
+    ic_cdk_timers::set_timer(std::time::Duration::ZERO, || {
+        ic_cdk::spawn(async {
+            let platform = STATE.with(|state| state.borrow().token_canister_id());
+            match ledger::decimals(platform).await {
+                // Tokens indexed before the decimals were known have no price yet
+                Ok(_) => STATE.with(|state| catalog::refresh_prices(&mut state.borrow_mut())),
+                Err(e) => ic_cdk::println!("Reading the platform ledger's decimals failed: {}", e),
+            }
+        })
+    });
+}
+
+// Queries cannot read the ledger, so a token whose supply was never read is not priced at all
+// rather than at the bottom of its curve
+pub fn known_supply(token: Principal) -> Result<Nat, FactoryError> {
+    cached_supply(token).ok_or_else(|| FactoryError::InvalidState(format!(
+        "Supply of {} has not been read from its ledger yet; use the quote update call instead", token
+    )))
+}
+
+pub fn cached_supply(token: Principal) -> Option<Nat> {
+    STATE.with(|state| state.borrow().token_supply.get(&token).map(Nat::from))
+}
+
+pub fn set_supply(token: Principal, supply: &Nat) -> Result<(), FactoryError> {
+    let supply = to_u128(supply)?;
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.token_supply.insert(token, supply);
+        catalog::refresh_price(&mut state, token);
+    });
+    Ok(())
+}
+
+// Read the circulating supply from the talent ledger the first time a token is priced
+pub async fn circulating_supply(token: Principal) -> Result<Nat, FactoryError> {
+    if let Some(supply) = cached_supply(token) {
+        return Ok(supply);
+    }
+
+    let (supply,): (Nat,) = ic_cdk::call(token, "icrc1_total_supply", ())
+        .await
+        .map_err(|e| ledger::call_failed(token, "icrc1_total_supply", e))?;
+    // Another call may have seeded the cache while we were waiting
+    if let Some(supply) = cached_supply(token) {
+        return Ok(supply);
+    }
+    set_supply(token, &supply)?;
+    Ok(supply)
+}
+
+// Cost in platform base units of `quantity` talent base units
+#[ic_cdk::query]
+pub fn quote_talent_token_purchase(token: Principal, quantity: Nat) -> Result<Nat, FactoryError> {
+    let metadata = STATE.with(|state| state.borrow().tokens.get(&token))
+        .ok_or(FactoryError::TokenNotFound(token))?;
+    let (pricing, token_decimals) = cached_pricing_of(token, &metadata)?;
+    let supply = known_supply(token)?;
+    purchase_cost(&pricing, &supply, &quantity, token_decimals)
+}
+
+// Price in platform base units of the next whole talent token
+#[ic_cdk::query]
+pub fn get_talent_token_price(token: Principal) -> Result<Nat, FactoryError> {
+    let metadata = STATE.with(|state| state.borrow().tokens.get(&token))
+        .ok_or(FactoryError::TokenNotFound(token))?;
+    let (pricing, token_decimals) = cached_pricing_of(token, &metadata)?;
+    let supply = known_supply(token)?;
+    spot_price(&pricing, &supply, token_decimals)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nat(value: u128) -> Nat {
+        Nat::from(value)
+    }
+
+    #[test]
+    fn unit_scales_by_decimals() {
+        assert_eq!(unit(0), nat(1));
+        assert_eq!(unit(8), nat(100_000_000));
+    }
+
+    #[test]
+    fn unit_price_is_per_whole_token() {
+        assert_eq!(unit_price(&nat(250_000_000), &nat(50_000_000), 8), Some(nat(500_000_000)));
+        assert_eq!(unit_price(&nat(1), &nat(0), 8), None);
+    }
+
+    #[test]
+    fn fixed_price_scales_with_decimals() {
+        let pricing = PricingModel::Fixed { price: nat(500_000_000) };
+        assert_eq!(spot_price(&pricing, &nat(0), 8).unwrap(), nat(500_000_000));
+        assert_eq!(purchase_cost(&pricing, &nat(0), &nat(100_000_000), 8).unwrap(), nat(500_000_000));
+        assert_eq!(purchase_cost(&pricing, &nat(0), &nat(50_000_000), 8).unwrap(), nat(250_000_000));
+        // A single base unit still costs something
+        assert_eq!(purchase_cost(&pricing, &nat(0), &nat(1), 8).unwrap(), nat(5));
+    }
+
+    #[test]
+    fn linear_cost_sums_every_step() {
+        let pricing = PricingModel::Linear { base_price: nat(100), slope: nat(10) };
+        assert_eq!(spot_price(&pricing, &nat(0), 0).unwrap(), nat(100));
+        assert_eq!(spot_price(&pricing, &nat(2), 0).unwrap(), nat(120));
+        assert_eq!(purchase_cost(&pricing, &nat(0), &nat(3), 0).unwrap(), nat(100 + 110 + 120));
+        assert_eq!(purchase_cost(&pricing, &nat(3), &nat(2), 0).unwrap(), nat(130 + 140));
+    }
+
+    #[test]
+    fn linear_cost_rounds_up_across_decimals() {
+        let pricing = PricingModel::Linear { base_price: nat(100), slope: nat(10) };
+        assert_eq!(spot_price(&pricing, &nat(250), 2).unwrap(), nat(125));
+        // 100 base units from zero: (100 * 100 * 100 + 10 * 4_950) / 100^2 = 104.95
+        assert_eq!(purchase_cost(&pricing, &nat(0), &nat(100), 2).unwrap(), nat(105));
+    }
+
+    #[test]
+    fn exponential_matches_the_geometric_sum() {
+        let pricing = PricingModel::Exponential { base_price: nat(1_000), growth_bps: 10_000 };
+        assert_eq!(spot_price(&pricing, &nat(0), 0).unwrap(), nat(1_000));
+        assert_eq!(spot_price(&pricing, &nat(3), 0).unwrap(), nat(8_000));
+        assert_eq!(purchase_cost(&pricing, &nat(0), &nat(3), 0).unwrap(), nat(1_000 + 2_000 + 4_000));
+        assert_eq!(purchase_cost(&pricing, &nat(3), &nat(1), 0).unwrap(), nat(8_000));
+    }
+
+    #[test]
+    fn exponential_handles_fractional_supply() {
+        let pricing = PricingModel::Exponential { base_price: nat(1_000), growth_bps: 10_000 };
+        // Half a token in: 1_000 * sqrt(2) = 1_414.21..., rounded up
+        assert_eq!(spot_price(&pricing, &nat(5), 1).unwrap(), nat(1_415));
+        let small = PricingModel::Exponential { base_price: nat(1_000_000), growth_bps: 100 };
+        // 1.01^0.5 = 1.004987...
+        assert_eq!(spot_price(&small, &nat(50), 2).unwrap(), nat(1_004_988));
+    }
+
+    #[test]
+    fn growth_factor_stops_at_the_limit() {
+        assert!(growth_factor(10_000, &nat(127), &nat(1)).is_ok());
+        assert!(growth_factor(10_000, &nat(128), &nat(1)).is_err());
+        let pricing = PricingModel::Exponential { base_price: nat(1), growth_bps: 10_000 };
+        assert!(spot_price(&pricing, &nat(200), 0).is_err());
+        assert!(purchase_cost(&pricing, &nat(100), &nat(100), 0).is_err());
+    }
+
+    #[test]
+    fn zero_quantity_is_refused() {
+        let pricing = PricingModel::Fixed { price: nat(1) };
+        assert!(purchase_cost(&pricing, &nat(0), &nat(0), 8).is_err());
+    }
+
+    #[test]
+    fn pricing_is_validated() {
+        assert!(validate_pricing(&PricingModel::Fixed { price: nat(0) }).is_err());
+        assert!(validate_pricing(&PricingModel::Linear { base_price: nat(0), slope: nat(0) }).is_err());
+        assert!(validate_pricing(&PricingModel::Exponential { base_price: nat(1), growth_bps: 0 }).is_err());
+        assert!(validate_pricing(&PricingModel::Exponential { base_price: nat(1), growth_bps: MAX_GROWTH_BPS + 1 }).is_err());
+        assert!(validate_pricing(&PricingModel::Linear { base_price: nat(0), slope: nat(1) }).is_ok());
+    }
+}
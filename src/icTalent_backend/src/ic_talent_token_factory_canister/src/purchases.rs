@@ -0,0 +1,268 @@
+use candid::{Nat, Principal};
+use crate::catalog;
+use crate::ledger::{self, PURCHASE_ESCROW_SUBACCOUNT, RESERVE_SUBACCOUNT};
+use crate::pricing;
+use crate::redemptions;
+use crate::trades;
+use crate::state_handler::{ensure_role, InFlightGuard, STATE};
+use crate::types::*;
+use ic_cdk::api::caller;
+use icrc_ledger_types::icrc1::account::Account;
+
+pub const PURCHASE: &str = "Purchase";
+
+// Every transfer of a purchase is named after its step, so a retried step is a ledger duplicate
+fn dedup(purchase: &Purchase, step: &str) -> ledger::Dedup {
+    ledger::Dedup::new(&format!("purchase/{}/{}", purchase.id, step), purchase.created_at)
+}
+
+fn is_terminal(status: &PurchaseStatus) -> bool {
+    matches!(status, PurchaseStatus::Completed | PurchaseStatus::Refunded | PurchaseStatus::Failed)
+}
+
+fn get_purchase_record(purchase_id: u64) -> Result<Purchase, FactoryError> {
+    STATE.with(|state| {
+        state.borrow().purchases.get(&purchase_id)
+            .ok_or(FactoryError::PurchaseNotFound(purchase_id))
+    })
+}
+
+fn save_purchase(mut purchase: Purchase) {
+    purchase.updated_at = ic_cdk::api::time();
+    STATE.with(|state| {
+        state.borrow_mut().purchases.insert(purchase.id, purchase);
+    });
+}
+
+pub fn create_purchase(payer: Account, receiver: Option<Account>, token: Principal, seller: Principal, quantity: Nat, total_cost: Nat, reserve_amount: Nat) -> u64 {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let purchase_id = state.purchases.last_key_value().map(|(id, _)| id + 1).unwrap_or(0);
+        let now = ic_cdk::api::time();
+        state.purchases.insert(purchase_id, Purchase {
+            id: purchase_id,
+            buyer: payer.owner,
+            token,
+            seller,
+            quantity,
+            total_cost,
+            status: PurchaseStatus::Pending,
+            payment_block: None,
+            mint_block: None,
+            settlement_block: None,
+            last_error: None,
+            created_at: now,
+            updated_at: now,
+            reserve_amount: Some(reserve_amount),
+            reserve_block: None,
+            payment_subaccount: payer.subaccount,
+            receiver,
+        });
+        purchase_id
+    })
+}
+
+// Run the purchase forward: escrow the payment, mint, pay the seller, then move the
+// reserve share to the reserve subaccount. A failed mint refunds the buyer straight away.
+pub async fn advance_purchase(purchase_id: u64) -> Result<Purchase, FactoryError> {
+    let _guard = InFlightGuard::acquire(PURCHASE, purchase_id)?;
+
+    loop {
+        let mut purchase = get_purchase_record(purchase_id)?;
+        let step = match purchase.status {
+            PurchaseStatus::Pending => escrow_payment(&mut purchase).await,
+            PurchaseStatus::Escrowed => match mint_tokens(&mut purchase).await {
+                Ok(()) => Ok(()),
+                // The mint may have gone through, so it is retried rather than refunded
+                Err(e) if !ledger::is_rejection(&e) => Err(e),
+                Err(e) => match refund_buyer(&mut purchase).await {
+                    Ok(()) => Err(FactoryError::RolledBack(Box::new(e))),
+                    // The payment stays in escrow for an admin to refund
+                    Err(refund_error) => {
+                        ic_cdk::println!("Refund of purchase {} failed: {}", purchase_id, refund_error);
+                        Err(e)
+                    }
+                },
+            },
+            PurchaseStatus::Minted => release_payment(&mut purchase).await,
+            PurchaseStatus::SellerPaid => fund_reserve(&mut purchase).await,
+            PurchaseStatus::Completed => return Ok(purchase),
+            PurchaseStatus::Refunded | PurchaseStatus::Failed => {
+                return Err(FactoryError::InvalidState(
+                    purchase.last_error.unwrap_or_else(|| format!("Purchase {} did not complete", purchase_id)),
+                ));
+            }
+        };
+
+        purchase.last_error = step.as_ref().err().map(|e| e.to_string());
+        if matches!(purchase.status, PurchaseStatus::Refunded | PurchaseStatus::Failed) {
+            release_reserved_supply(&purchase);
+        }
+        save_purchase(purchase);
+        step?;
+    }
+}
+
+async fn escrow_payment(purchase: &mut Purchase) -> Result<(), FactoryError> {
+    let ledger = STATE.with(|state| state.borrow().token_canister_id());
+    match ledger::transfer_from(
+        ledger,
+        purchase.payer_account(),
+        ledger::factory_account(PURCHASE_ESCROW_SUBACCOUNT),
+        purchase.total_cost.clone(),
+        dedup(purchase, "escrow"),
+    ).await {
+        Ok(payment_block) => {
+            purchase.payment_block = Some(payment_block);
+            purchase.status = PurchaseStatus::Escrowed;
+            Ok(())
+        }
+        Err(e) => {
+            // Nothing was taken from the buyer, so there is nothing to undo. After a failed
+            // call the purchase stays Pending for a retry to find out.
+            if ledger::is_rejection(&e) {
+                purchase.status = PurchaseStatus::Failed;
+            }
+            Err(e)
+        }
+    }
+}
+
+async fn mint_tokens(purchase: &mut Purchase) -> Result<(), FactoryError> {
+    let mint_block = ledger::mint(purchase.token, purchase.receiver_account(), purchase.quantity.clone(), dedup(purchase, "mint")).await?;
+
+    purchase.mint_block = Some(mint_block);
+    purchase.status = PurchaseStatus::Minted;
+
+    // Log the trade once the tokens are out
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let trade = trades::trade_from_purchase(&state, purchase, ic_cdk::api::time());
+        trades::append_trade(&mut state, trade);
+        catalog::add_holding(&mut state, purchase.token, purchase.receiver_account().owner, &purchase.quantity);
+    });
+
+    Ok(())
+}
+
+async fn release_payment(purchase: &mut Purchase) -> Result<(), FactoryError> {
+    let ledger = STATE.with(|state| state.borrow().token_canister_id());
+    let reserve_amount = purchase.reserve_amount.clone().unwrap_or_default();
+    let seller_amount = pricing::checked_sub(&purchase.total_cost, &reserve_amount)?;
+    let release_block = ledger::release(ledger, PURCHASE_ESCROW_SUBACCOUNT, Account::from(purchase.seller), seller_amount, dedup(purchase, "release")).await?;
+
+    purchase.settlement_block = release_block;
+    purchase.status = PurchaseStatus::SellerPaid;
+    Ok(())
+}
+
+async fn fund_reserve(purchase: &mut Purchase) -> Result<(), FactoryError> {
+    let reserve_amount = purchase.reserve_amount.clone().unwrap_or_default();
+    if reserve_amount > 0u32 {
+        let ledger = STATE.with(|state| state.borrow().token_canister_id());
+        let fee = ledger::fee(ledger).await?;
+        if reserve_amount > fee {
+            let credited = reserve_amount - fee;
+            let reserve_block = ledger::transfer(
+                ledger,
+                Some(PURCHASE_ESCROW_SUBACCOUNT),
+                ledger::factory_account(RESERVE_SUBACCOUNT),
+                credited.clone(),
+                dedup(purchase, "reserve"),
+            )
+            .await?;
+
+            purchase.reserve_block = Some(reserve_block);
+            redemptions::credit_reserve(purchase.token, &credited)?;
+        }
+    }
+
+    purchase.status = PurchaseStatus::Completed;
+    Ok(())
+}
+
+async fn refund_buyer(purchase: &mut Purchase) -> Result<(), FactoryError> {
+    let ledger = STATE.with(|state| state.borrow().token_canister_id());
+    let refund_block = ledger::release(ledger, PURCHASE_ESCROW_SUBACCOUNT, purchase.payer_account(), purchase.total_cost.clone(), dedup(purchase, "refund")).await?;
+
+    purchase.settlement_block = refund_block;
+    purchase.status = PurchaseStatus::Refunded;
+    Ok(())
+}
+
+// Tokens of a purchase that will never be minted go back to the curve
+fn release_reserved_supply(purchase: &Purchase) {
+    if let Some(supply) = pricing::cached_supply(purchase.token) {
+        let supply = if supply > purchase.quantity { supply - purchase.quantity.clone() } else { Nat::from(0u32) };
+        if let Err(e) = pricing::set_supply(purchase.token, &supply) {
+            ic_cdk::println!("Failed to release supply of purchase {}: {}", purchase.id, e);
+        }
+    }
+}
+
+#[ic_cdk::query]
+pub fn get_purchase(purchase_id: u64) -> Result<Purchase, FactoryError> {
+    let purchase = get_purchase_record(purchase_id)?;
+    if purchase.buyer != caller() {
+        ensure_role(caller(), Role::Moderator)?;
+    }
+    Ok(purchase)
+}
+
+// Purchases that stopped on an error with funds still in escrow
+#[ic_cdk::query]
+pub fn get_stuck_purchases() -> Result<Vec<Purchase>, FactoryError> {
+    ensure_role(caller(), Role::Treasurer)?;
+
+    STATE.with(|state| {
+        Ok(state.borrow().purchases.values()
+            .filter(|purchase| !is_terminal(&purchase.status)
+                && purchase.last_error.is_some()
+                && !InFlightGuard::is_held(PURCHASE, purchase.id))
+            .collect())
+    })
+}
+
+#[ic_cdk::update]
+pub async fn retry_purchase(purchase_id: u64) -> Result<Purchase, FactoryError> {
+    ensure_role(caller(), Role::Treasurer)?;
+    advance_purchase(purchase_id).await
+}
+
+// Give the escrowed payment back for a purchase whose tokens were never minted. The last
+// mint attempt may still have landed, so it is repeated with the same dedup first: a mint
+// that goes through, or turns out to be a duplicate, leaves the purchase to be retried, and
+// only one the ledger rejects is refunded.
+#[ic_cdk::update]
+pub async fn refund_purchase(purchase_id: u64) -> Result<Purchase, FactoryError> {
+    ensure_role(caller(), Role::Treasurer)?;
+    let _guard = InFlightGuard::acquire(PURCHASE, purchase_id)?;
+
+    let mut purchase = get_purchase_record(purchase_id)?;
+    if purchase.status != PurchaseStatus::Escrowed {
+        return Err(FactoryError::InvalidState(format!("Purchase {} has no escrowed payment to refund", purchase_id)));
+    }
+
+    match mint_tokens(&mut purchase).await {
+        Ok(()) => {
+            let e = FactoryError::InvalidState(format!("Purchase {} was minted; retry it to pay the seller instead of refunding", purchase_id));
+            purchase.last_error = Some(e.to_string());
+            save_purchase(purchase);
+            return Err(e);
+        }
+        Err(e) if !ledger::is_rejection(&e) => {
+            purchase.last_error = Some(e.to_string());
+            save_purchase(purchase);
+            return Err(e);
+        }
+        Err(_) => {}
+    }
+
+    let result = refund_buyer(&mut purchase).await;
+    purchase.last_error = result.as_ref().err().map(|e| e.to_string());
+    if result.is_ok() {
+        release_reserved_supply(&purchase);
+    }
+    save_purchase(purchase.clone());
+    result.map(|()| purchase)
+}
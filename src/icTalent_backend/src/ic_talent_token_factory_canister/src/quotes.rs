@@ -0,0 +1,50 @@
+use candid::Principal;
+use crate::ledger;
+use crate::pricing;
+use crate::state_handler::STATE;
+use crate::token_pool;
+use crate::types::*;
+use ic_cdk::api::caller;
+use icrc_ledger_types::icrc1::account::Account;
+
+// Everything a client needs to approve before calling create_talent_token_canister,
+// buy_talent_token or top_up_faucet_treasury. Reads the ledger, so this is an update call.
+#[ic_cdk::update]
+pub async fn quote(args: QuoteArgs) -> Result<Quote, FactoryError> {
+    let caller = caller();
+    if caller == Principal::anonymous() {
+        return Err(FactoryError::AnonymousCaller);
+    }
+
+    let amount = match &args.kind {
+        QuoteKind::Creation => token_pool::creation_fee().await?,
+        QuoteKind::Purchase { token, quantity } => {
+            let metadata = STATE.with(|state| state.borrow().tokens.get(token))
+                .ok_or(FactoryError::TokenNotFound(*token))?;
+            pricing::current_purchase_cost(*token, &metadata, quantity).await?
+        }
+        QuoteKind::FaucetTopUp { amount } => amount.clone(),
+    };
+
+    let ledger = STATE.with(|state| state.borrow().token_canister_id());
+    let account = Account { owner: caller, subaccount: args.from_subaccount };
+    let (fee, allowance, balance) = futures::try_join!(
+        ledger::fee(ledger),
+        ledger::allowance(ledger, account),
+        ledger::balance_of(ledger, account),
+    )?;
+
+    let approve_amount = amount.clone() + fee.clone();
+    Ok(Quote {
+        ledger,
+        spender: ledger::spender(),
+        allowance_sufficient: allowance.allowance >= approve_amount,
+        balance_sufficient: balance >= approve_amount,
+        amount,
+        fee,
+        approve_amount,
+        current_allowance: allowance.allowance,
+        allowance_expires_at: allowance.expires_at,
+        balance,
+    })
+}
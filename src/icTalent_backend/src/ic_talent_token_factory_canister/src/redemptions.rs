@@ -0,0 +1,274 @@
+use candid::{Nat, Principal};
+use crate::catalog;
+use crate::idempotency;
+use crate::ledger::{self, RESERVE_SUBACCOUNT};
+use crate::market;
+use crate::pricing;
+use crate::state_handler::{ensure_role, InFlightGuard, STATE};
+use crate::types::*;
+use ic_cdk::api::caller;
+use icrc_ledger_types::icrc1::account::Subaccount;
+
+pub const REDEMPTION: &str = "Redemption";
+const MAX_RESERVE_SHARE_BPS: u16 = 10_000;
+
+fn reserve_share_bps() -> u16 {
+    STATE.with(|state| state.borrow().config.get().reserve_share_bps.unwrap_or(0))
+}
+
+// Part of a purchase payment that goes to the token's reserve
+pub fn reserve_share(total_cost: &Nat) -> Nat {
+    total_cost.clone() * Nat::from(reserve_share_bps()) / 10_000u32
+}
+
+pub fn reserve_of(token: Principal) -> Nat {
+    STATE.with(|state| Nat::from(state.borrow().token_reserves.get(&token).unwrap_or_default()))
+}
+
+pub fn credit_reserve(token: Principal, amount: &Nat) -> Result<(), FactoryError> {
+    let reserve = pricing::to_u128(&(reserve_of(token) + amount.clone()))?;
+    STATE.with(|state| {
+        state.borrow_mut().token_reserves.insert(token, reserve);
+    });
+    Ok(())
+}
+
+fn debit_reserve(token: Principal, amount: &Nat) -> Result<(), FactoryError> {
+    let available = reserve_of(token);
+    if available < *amount {
+        return Err(FactoryError::InsufficientReserve { required: amount.clone(), available });
+    }
+    let reserve = pricing::to_u128(&pricing::checked_sub(&available, amount)?)?;
+    STATE.with(|state| {
+        state.borrow_mut().token_reserves.insert(token, reserve);
+    });
+    Ok(())
+}
+
+// What the reserve pays for the last `quantity` tokens on the curve: the reserve
+// share of what they cost to buy, so the reserve stays funded for every token sold back
+pub fn sale_value(pricing: &PricingModel, token_decimals: u8, supply: &Nat, quantity: &Nat) -> Result<Nat, FactoryError> {
+    if *supply < *quantity {
+        return Err(FactoryError::InvalidArgument(format!("Only {} tokens are in circulation", supply)));
+    }
+    let start = pricing::checked_sub(supply, quantity)?;
+    let cost = pricing::purchase_cost(pricing, &start, quantity, token_decimals)?;
+    Ok(cost * Nat::from(reserve_share_bps()) / 10_000u32)
+}
+
+fn dedup(redemption: &Redemption, step: &str) -> ledger::Dedup {
+    ledger::Dedup::new(&format!("redemption/{}/{}", redemption.id, step), redemption.created_at)
+}
+
+fn get_redemption_record(redemption_id: u64) -> Result<Redemption, FactoryError> {
+    STATE.with(|state| {
+        state.borrow().redemptions.get(&redemption_id)
+            .ok_or(FactoryError::RedemptionNotFound(redemption_id))
+    })
+}
+
+fn save_redemption(mut redemption: Redemption) {
+    redemption.updated_at = ic_cdk::api::time();
+    STATE.with(|state| {
+        state.borrow_mut().redemptions.insert(redemption.id, redemption);
+    });
+}
+
+// A retry with the same idempotency key resumes the first call's redemption instead of selling again
+#[ic_cdk::update]
+pub async fn sell_talent_token(canister_id: Principal, quantity: Nat, from_subaccount: Option<Subaccount>, idempotency_key: Option<String>) -> Result<Redemption, FactoryError> {
+    let seller = caller();
+    if seller == Principal::anonymous() {
+        return Err(FactoryError::AnonymousCaller);
+    }
+    if let Some(key) = &idempotency_key {
+        idempotency::validate_key(key)?;
+        let processed = STATE.with(|state| idempotency::find(&state.borrow(), seller, REDEMPTION, key));
+        if let Some(redemption_id) = processed.and_then(|processed| processed.record_id) {
+            return advance_redemption(redemption_id).await;
+        }
+    }
+
+    let token_metadata = STATE.with(|state| {
+        state.borrow().tokens.get(&canister_id)
+            .ok_or(FactoryError::TokenNotFound(canister_id))
+    })?;
+    if quantity == 0u32 {
+        return Err(FactoryError::InvalidArgument("Quantity must be greater than zero".to_string()));
+    }
+    let (pricing_model, token_decimals) = pricing::pricing_of(canister_id, &token_metadata).await?;
+    pricing::circulating_supply(canister_id).await?;
+
+    // Take the payout out of the reserve and the tokens off the curve before anything
+    // is awaited, so concurrent sales cannot spend the same reserve
+    let redemption_id = STATE.with(|state| {
+        if let Some(key) = &idempotency_key {
+            if let Some(redemption_id) = idempotency::find(&state.borrow(), seller, REDEMPTION, key).and_then(|processed| processed.record_id) {
+                return Ok(redemption_id);
+            }
+        }
+        let supply = Nat::from(state.borrow().token_supply.get(&canister_id).unwrap_or_default());
+        let payout = sale_value(&pricing_model, token_decimals, &supply, &quantity)?;
+        if payout == 0u32 {
+            return Err(FactoryError::InvalidState("Redemptions are not funded for this token".to_string()));
+        }
+        debit_reserve(canister_id, &payout)?;
+        pricing::set_supply(canister_id, &pricing::checked_sub(&supply, &quantity)?)?;
+
+        let mut state = state.borrow_mut();
+        let redemption_id = state.redemptions.last_key_value().map(|(id, _)| id + 1).unwrap_or(0);
+        let now = ic_cdk::api::time();
+        state.redemptions.insert(redemption_id, Redemption {
+            id: redemption_id,
+            seller,
+            token: canister_id,
+            quantity: quantity.clone(),
+            payout,
+            status: RedemptionStatus::Pending,
+            burn_block: None,
+            payout_block: None,
+            last_error: None,
+            created_at: now,
+            updated_at: now,
+            subaccount: from_subaccount,
+        });
+        if let Some(key) = &idempotency_key {
+            idempotency::remember(&mut state, seller, REDEMPTION, key, Some(redemption_id));
+        }
+        Ok(redemption_id)
+    })?;
+
+    advance_redemption(redemption_id).await
+}
+
+// Burn the seller's tokens, then pay them out of the reserve
+async fn advance_redemption(redemption_id: u64) -> Result<Redemption, FactoryError> {
+    let _guard = InFlightGuard::acquire(REDEMPTION, redemption_id)?;
+
+    loop {
+        let mut redemption = get_redemption_record(redemption_id)?;
+        let step = match redemption.status {
+            RedemptionStatus::Pending => burn_tokens(&mut redemption).await,
+            RedemptionStatus::Burned => pay_out(&mut redemption).await,
+            RedemptionStatus::Paid => return Ok(redemption),
+            RedemptionStatus::Failed => {
+                return Err(FactoryError::InvalidState(
+                    redemption.last_error.unwrap_or_else(|| format!("Redemption {} failed", redemption_id)),
+                ));
+            }
+        };
+
+        redemption.last_error = step.as_ref().err().map(|e| e.to_string());
+        save_redemption(redemption);
+        step?;
+    }
+}
+
+async fn burn_tokens(redemption: &mut Redemption) -> Result<(), FactoryError> {
+    match ledger::burn_from(redemption.token, redemption.seller_account(), redemption.quantity.clone(), dedup(redemption, "burn")).await {
+        Ok(burn_block) => {
+            redemption.burn_block = Some(burn_block);
+            redemption.status = RedemptionStatus::Burned;
+            STATE.with(|state| {
+                let mut state = state.borrow_mut();
+                market::record_fill(&mut state, redemption.token, ic_cdk::api::time(), &redemption.quantity, &redemption.payout);
+                catalog::add_volume(&mut state, redemption.token, &redemption.payout);
+                catalog::remove_holding(&mut state, redemption.token, redemption.seller, &redemption.quantity);
+            });
+            Ok(())
+        }
+        // The burn may have gone through, so the redemption stays Pending for a retry with the same dedup
+        Err(e) if !ledger::is_rejection(&e) => Err(e),
+        Err(e) => {
+            // Nothing left the seller, so give the reserve and supply back
+            credit_reserve(redemption.token, &redemption.payout)?;
+            let supply = pricing::cached_supply(redemption.token).unwrap_or_default();
+            pricing::set_supply(redemption.token, &(supply + redemption.quantity.clone()))?;
+            redemption.status = RedemptionStatus::Failed;
+            Err(e)
+        }
+    }
+}
+
+async fn pay_out(redemption: &mut Redemption) -> Result<(), FactoryError> {
+    let ledger = STATE.with(|state| state.borrow().token_canister_id());
+    let payout_block = ledger::release(ledger, RESERVE_SUBACCOUNT, redemption.seller_account(), redemption.payout.clone(), dedup(redemption, "payout")).await?;
+
+    redemption.payout_block = payout_block;
+    redemption.status = RedemptionStatus::Paid;
+    Ok(())
+}
+
+#[ic_cdk::query]
+pub fn quote_talent_token_sale(canister_id: Principal, quantity: Nat) -> Result<Nat, FactoryError> {
+    let token_metadata = STATE.with(|state| state.borrow().tokens.get(&canister_id))
+        .ok_or(FactoryError::TokenNotFound(canister_id))?;
+    let (pricing_model, token_decimals) = pricing::cached_pricing_of(canister_id, &token_metadata)?;
+    let supply = pricing::known_supply(canister_id)?;
+    sale_value(&pricing_model, token_decimals, &supply, &quantity)
+}
+
+#[ic_cdk::query]
+pub fn get_token_reserve(canister_id: Principal) -> Result<Nat, FactoryError> {
+    STATE.with(|state| {
+        if !state.borrow().tokens.contains_key(&canister_id) {
+            return Err(FactoryError::TokenNotFound(canister_id));
+        }
+        Ok(())
+    })?;
+    Ok(reserve_of(canister_id))
+}
+
+#[ic_cdk::query]
+pub fn get_token_reserves() -> Result<Vec<(Principal, Nat)>, FactoryError> {
+    STATE.with(|state| {
+        Ok(state.borrow().token_reserves.iter().map(|(token, reserve)| (token, Nat::from(reserve))).collect())
+    })
+}
+
+#[ic_cdk::query]
+pub fn get_reserve_share() -> Result<u16, FactoryError> {
+    Ok(reserve_share_bps())
+}
+
+#[ic_cdk::update]
+pub fn set_reserve_share(reserve_share_bps: u16) -> Result<String, FactoryError> {
+    ensure_role(caller(), Role::Treasurer)?;
+    if reserve_share_bps > MAX_RESERVE_SHARE_BPS {
+        return Err(FactoryError::InvalidArgument(format!("Reserve share cannot exceed {} basis points", MAX_RESERVE_SHARE_BPS)));
+    }
+
+    STATE.with(|state| {
+        state.borrow_mut().update_config(|config| config.reserve_share_bps = Some(reserve_share_bps));
+    });
+    Ok(format!("Reserve share set to {} basis points", reserve_share_bps))
+}
+
+#[ic_cdk::query]
+pub fn get_redemption(redemption_id: u64) -> Result<Redemption, FactoryError> {
+    let redemption = get_redemption_record(redemption_id)?;
+    if redemption.seller != caller() {
+        ensure_role(caller(), Role::Moderator)?;
+    }
+    Ok(redemption)
+}
+
+// Redemptions whose tokens were burned but whose payout failed
+#[ic_cdk::query]
+pub fn get_stuck_redemptions() -> Result<Vec<Redemption>, FactoryError> {
+    ensure_role(caller(), Role::Treasurer)?;
+
+    STATE.with(|state| {
+        Ok(state.borrow().redemptions.values()
+            .filter(|redemption| matches!(redemption.status, RedemptionStatus::Pending | RedemptionStatus::Burned)
+                && redemption.last_error.is_some()
+                && !InFlightGuard::is_held(REDEMPTION, redemption.id))
+            .collect())
+    })
+}
+
+#[ic_cdk::update]
+pub async fn retry_redemption(redemption_id: u64) -> Result<Redemption, FactoryError> {
+    ensure_role(caller(), Role::Treasurer)?;
+    advance_redemption(redemption_id).await
+}
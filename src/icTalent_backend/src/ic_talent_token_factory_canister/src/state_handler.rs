@@ -1,23 +1,108 @@
-use candid::{Decode, Encode, Principal};
+use candid::{Decode, Encode, Nat, Principal};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{DefaultMemoryImpl, Storable, StableBTreeMap};
+use ic_stable_structures::{DefaultMemoryImpl, Storable, StableBTreeMap, StableCell};
 use std::borrow::Cow;
 use std::cell::RefCell;
+use std::collections::BTreeSet;
 
 use crate::types::*;
 
 // Define Memory Types
 pub type Memory = VirtualMemory<DefaultMemoryImpl>;
 pub type TokenMap = StableBTreeMap<Principal, TokenMetadata, Memory>;
-pub type FaucetRequestMap = StableBTreeMap<Principal, FaucetTokenRequest, Memory>;
+pub type LegacyFaucetRequestMap = StableBTreeMap<Principal, FaucetTokenRequest, Memory>;
 pub type TalentTokenMap = StableBTreeMap<Principal, Principal, Memory>;
-pub type PurchaseHistoryMap = StableBTreeMap<Principal, PrincipalVec, Memory>;
+pub type LegacyPurchaseHistoryMap = StableBTreeMap<Principal, PrincipalVec, Memory>;
+pub type ConfigCell = StableCell<FactoryConfig, Memory>;
+pub type WasmModuleCell = StableCell<Vec<u8>, Memory>;
+pub type WasmVersionMap = StableBTreeMap<String, WasmVersion, Memory>;
+pub type WasmModuleMap = StableBTreeMap<String, Vec<u8>, Memory>;
+pub type WasmUploadMap = StableBTreeMap<String, WasmUpload, Memory>;
+pub type LedgerUpgradeRunMap = StableBTreeMap<u64, LedgerUpgradeRun, Memory>;
+pub type CreationJobMap = StableBTreeMap<u64, CreationJob, Memory>;
+pub type PurchaseMap = StableBTreeMap<u64, Purchase, Memory>;
+pub type TokenSupplyMap = StableBTreeMap<Principal, u128, Memory>;
+pub type TokenReserveMap = StableBTreeMap<Principal, u128, Memory>;
+pub type RedemptionMap = StableBTreeMap<u64, Redemption, Memory>;
+pub type RoleMap = StableBTreeMap<Principal, RoleSet, Memory>;
+pub type RoleAuditLog = StableBTreeMap<u64, RoleAuditEntry, Memory>;
+pub type FaucetRequestMap = StableBTreeMap<u64, FaucetRequest, Memory>;
+pub type FaucetRequestsByUser = StableBTreeMap<(Principal, u64), (), Memory>;
+pub type FaucetPolicyCell = StableCell<FaucetPolicy, Memory>;
+pub type VerifiedUserMap = StableBTreeMap<Principal, VerifiedUser, Memory>;
+pub type PaymentMap = StableBTreeMap<u64, Payment, Memory>;
+pub type PaymentsByPayer = StableBTreeMap<(Principal, u64), (), Memory>;
+pub type ProcessedKeyMap = StableBTreeMap<[u8; 32], ProcessedKey, Memory>;
+pub type ProcessedKeysByTime = StableBTreeMap<(u64, [u8; 32]), (), Memory>;
+pub type LedgerDecimalsMap = StableBTreeMap<Principal, u8, Memory>;
+pub type TradeLog = StableBTreeMap<u64, Trade, Memory>;
+pub type TradeIndex = StableBTreeMap<(Principal, u64), (), Memory>;
+pub type TradesByTime = StableBTreeMap<(u64, u64), (), Memory>;
+pub type CandleMap = StableBTreeMap<(Principal, u64), Candle, Memory>;
+pub type CatalogIndex = StableBTreeMap<Vec<u8>, Principal, Memory>;
+pub type TokenStatsMap = StableBTreeMap<Principal, TokenStats, Memory>;
+pub type TokenPositionMap = StableBTreeMap<(Principal, Principal), u128, Memory>;
+pub type TokenHoldingsIndex = StableBTreeMap<(Principal, Principal), (), Memory>;
 
 // Memory IDs for Maps
 const TOKEN_MAP_MEMORY_ID: MemoryId = MemoryId::new(0);
-const FAUCET_REQUEST_MAP_MEMORY_ID: MemoryId = MemoryId::new(1);
+// Held one FaucetTokenRequest per user before per-request records, only read to migrate it
+const LEGACY_FAUCET_REQUEST_MAP_MEMORY_ID: MemoryId = MemoryId::new(1);
 const TALENT_TOKEN_MAP_MEMORY_ID: MemoryId = MemoryId::new(2);
-const PURCHASE_HISTORY_MAP_MEMORY_ID: MemoryId = MemoryId::new(3);
+// Held the deduplicated tokens bought per user before the trade log, only read to migrate it
+const LEGACY_PURCHASE_HISTORY_MAP_MEMORY_ID: MemoryId = MemoryId::new(3);
+
+// Memory IDs for Cells
+const CONFIG_CELL_MEMORY_ID: MemoryId = MemoryId::new(4);
+// Held the single ledger WASM before the version registry, only read to migrate it
+const WASM_MODULE_CELL_MEMORY_ID: MemoryId = MemoryId::new(5);
+
+// Memory IDs for the WASM registry
+const WASM_VERSION_MAP_MEMORY_ID: MemoryId = MemoryId::new(6);
+const WASM_MODULE_MAP_MEMORY_ID: MemoryId = MemoryId::new(7);
+const WASM_UPLOAD_MAP_MEMORY_ID: MemoryId = MemoryId::new(8);
+const LEDGER_UPGRADE_RUN_MAP_MEMORY_ID: MemoryId = MemoryId::new(9);
+const CREATION_JOB_MAP_MEMORY_ID: MemoryId = MemoryId::new(10);
+const PURCHASE_MAP_MEMORY_ID: MemoryId = MemoryId::new(11);
+const TOKEN_SUPPLY_MAP_MEMORY_ID: MemoryId = MemoryId::new(12);
+const TOKEN_RESERVE_MAP_MEMORY_ID: MemoryId = MemoryId::new(13);
+const REDEMPTION_MAP_MEMORY_ID: MemoryId = MemoryId::new(14);
+
+// Memory IDs for access control
+const ROLE_MAP_MEMORY_ID: MemoryId = MemoryId::new(15);
+const ROLE_AUDIT_LOG_MEMORY_ID: MemoryId = MemoryId::new(16);
+
+// Memory IDs for the faucet
+const FAUCET_REQUEST_MAP_MEMORY_ID: MemoryId = MemoryId::new(17);
+const FAUCET_REQUESTS_BY_USER_MEMORY_ID: MemoryId = MemoryId::new(18);
+const FAUCET_POLICY_CELL_MEMORY_ID: MemoryId = MemoryId::new(19);
+const VERIFIED_USER_MAP_MEMORY_ID: MemoryId = MemoryId::new(20);
+
+// Memory IDs for the payments log
+const PAYMENT_MAP_MEMORY_ID: MemoryId = MemoryId::new(21);
+const PAYMENTS_BY_PAYER_MEMORY_ID: MemoryId = MemoryId::new(22);
+
+// Memory IDs for client idempotency keys
+const PROCESSED_KEY_MAP_MEMORY_ID: MemoryId = MemoryId::new(23);
+const PROCESSED_KEYS_BY_TIME_MEMORY_ID: MemoryId = MemoryId::new(24);
+const LEDGER_DECIMALS_MAP_MEMORY_ID: MemoryId = MemoryId::new(25);
+
+// Memory IDs for the trade log
+const TRADE_LOG_MEMORY_ID: MemoryId = MemoryId::new(26);
+const TRADES_BY_BUYER_MEMORY_ID: MemoryId = MemoryId::new(27);
+const TRADES_BY_TOKEN_MEMORY_ID: MemoryId = MemoryId::new(28);
+const TRADES_BY_TIME_MEMORY_ID: MemoryId = MemoryId::new(29);
+
+// Memory IDs for the price history, one candle map per interval
+const HOURLY_CANDLE_MAP_MEMORY_ID: MemoryId = MemoryId::new(30);
+const DAILY_CANDLE_MAP_MEMORY_ID: MemoryId = MemoryId::new(31);
+const WEEKLY_CANDLE_MAP_MEMORY_ID: MemoryId = MemoryId::new(32);
+
+// Memory IDs for the token catalog
+const CATALOG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(33);
+const TOKEN_STATS_MAP_MEMORY_ID: MemoryId = MemoryId::new(34);
+const TOKEN_POSITION_MAP_MEMORY_ID: MemoryId = MemoryId::new(35);
+const TOKEN_HOLDINGS_INDEX_MEMORY_ID: MemoryId = MemoryId::new(36);
 
 
 
@@ -27,44 +112,419 @@ thread_local! {
         MemoryManager::init(DefaultMemoryImpl::default())
     );
 
-    pub static WASM_MODULE: RefCell<Vec<u8>> = RefCell::new(Vec::new());
-
     pub static STATE: RefCell<State> = RefCell::new(
         MEMORY_MANAGER.with(|mm| State {
             tokens: TokenMap::init(mm.borrow().get(TOKEN_MAP_MEMORY_ID)),
-            faucet_requests: FaucetRequestMap::init(mm.borrow().get(FAUCET_REQUEST_MAP_MEMORY_ID)),
             talent_token_map: TalentTokenMap::init(mm.borrow().get(TALENT_TOKEN_MAP_MEMORY_ID)),
-            purchase_history: PurchaseHistoryMap::init(mm.borrow().get(PURCHASE_HISTORY_MAP_MEMORY_ID)),
-            admin: ic_cdk::api::id(),
-            token_canister_id: Principal::anonymous(),
-            is_admin_registered: false,
+            config: ConfigCell::init(mm.borrow().get(CONFIG_CELL_MEMORY_ID), FactoryConfig::default())
+                .expect("Failed to initialize config cell"),
+            wasm_versions: WasmVersionMap::init(mm.borrow().get(WASM_VERSION_MAP_MEMORY_ID)),
+            wasm_modules: WasmModuleMap::init(mm.borrow().get(WASM_MODULE_MAP_MEMORY_ID)),
+            wasm_uploads: WasmUploadMap::init(mm.borrow().get(WASM_UPLOAD_MAP_MEMORY_ID)),
+            ledger_upgrade_runs: LedgerUpgradeRunMap::init(mm.borrow().get(LEDGER_UPGRADE_RUN_MAP_MEMORY_ID)),
+            creation_jobs: CreationJobMap::init(mm.borrow().get(CREATION_JOB_MAP_MEMORY_ID)),
+            purchases: PurchaseMap::init(mm.borrow().get(PURCHASE_MAP_MEMORY_ID)),
+            token_supply: TokenSupplyMap::init(mm.borrow().get(TOKEN_SUPPLY_MAP_MEMORY_ID)),
+            token_reserves: TokenReserveMap::init(mm.borrow().get(TOKEN_RESERVE_MAP_MEMORY_ID)),
+            redemptions: RedemptionMap::init(mm.borrow().get(REDEMPTION_MAP_MEMORY_ID)),
+            roles: RoleMap::init(mm.borrow().get(ROLE_MAP_MEMORY_ID)),
+            role_audit_log: RoleAuditLog::init(mm.borrow().get(ROLE_AUDIT_LOG_MEMORY_ID)),
+            faucet_requests: FaucetRequestMap::init(mm.borrow().get(FAUCET_REQUEST_MAP_MEMORY_ID)),
+            faucet_requests_by_user: FaucetRequestsByUser::init(mm.borrow().get(FAUCET_REQUESTS_BY_USER_MEMORY_ID)),
+            faucet_policy: FaucetPolicyCell::init(mm.borrow().get(FAUCET_POLICY_CELL_MEMORY_ID), FaucetPolicy::default())
+                .expect("Failed to initialize faucet policy cell"),
+            verified_users: VerifiedUserMap::init(mm.borrow().get(VERIFIED_USER_MAP_MEMORY_ID)),
+            payments: PaymentMap::init(mm.borrow().get(PAYMENT_MAP_MEMORY_ID)),
+            payments_by_payer: PaymentsByPayer::init(mm.borrow().get(PAYMENTS_BY_PAYER_MEMORY_ID)),
+            processed_keys: ProcessedKeyMap::init(mm.borrow().get(PROCESSED_KEY_MAP_MEMORY_ID)),
+            processed_keys_by_time: ProcessedKeysByTime::init(mm.borrow().get(PROCESSED_KEYS_BY_TIME_MEMORY_ID)),
+            ledger_decimals: LedgerDecimalsMap::init(mm.borrow().get(LEDGER_DECIMALS_MAP_MEMORY_ID)),
+            trades: TradeLog::init(mm.borrow().get(TRADE_LOG_MEMORY_ID)),
+            trades_by_buyer: TradeIndex::init(mm.borrow().get(TRADES_BY_BUYER_MEMORY_ID)),
+            trades_by_token: TradeIndex::init(mm.borrow().get(TRADES_BY_TOKEN_MEMORY_ID)),
+            trades_by_time: TradesByTime::init(mm.borrow().get(TRADES_BY_TIME_MEMORY_ID)),
+            hourly_candles: CandleMap::init(mm.borrow().get(HOURLY_CANDLE_MAP_MEMORY_ID)),
+            daily_candles: CandleMap::init(mm.borrow().get(DAILY_CANDLE_MAP_MEMORY_ID)),
+            weekly_candles: CandleMap::init(mm.borrow().get(WEEKLY_CANDLE_MAP_MEMORY_ID)),
+            catalog_index: CatalogIndex::init(mm.borrow().get(CATALOG_INDEX_MEMORY_ID)),
+            token_stats: TokenStatsMap::init(mm.borrow().get(TOKEN_STATS_MAP_MEMORY_ID)),
+            token_positions: TokenPositionMap::init(mm.borrow().get(TOKEN_POSITION_MAP_MEMORY_ID)),
+            token_holdings: TokenHoldingsIndex::init(mm.borrow().get(TOKEN_HOLDINGS_INDEX_MEMORY_ID)),
         })
     );
+
+    // Multi-step records currently being advanced by an in-flight call
+    static IN_FLIGHT: RefCell<BTreeSet<(&'static str, u64)>> = const { RefCell::new(BTreeSet::new()) };
 }
 
 // State to manage all maps and variables
 pub struct State {
     pub tokens: TokenMap,
-    pub faucet_requests: FaucetRequestMap,
     pub talent_token_map: TalentTokenMap,
-    pub purchase_history: PurchaseHistoryMap,
-    pub admin: Principal,
-    pub token_canister_id: Principal,
-    pub is_admin_registered: bool,
+    pub config: ConfigCell,
+    pub wasm_versions: WasmVersionMap,
+    pub wasm_modules: WasmModuleMap,
+    pub wasm_uploads: WasmUploadMap,
+    pub ledger_upgrade_runs: LedgerUpgradeRunMap,
+    pub creation_jobs: CreationJobMap,
+    pub purchases: PurchaseMap,
+    // Circulating supply per talent token, including purchases still in flight
+    pub token_supply: TokenSupplyMap,
+    // Platform tokens held for redemptions per talent token, in the reserve subaccount
+    pub token_reserves: TokenReserveMap,
+    pub redemptions: RedemptionMap,
+    // Roles granted explicitly; the configured admin is always a SuperAdmin on top of these
+    pub roles: RoleMap,
+    pub role_audit_log: RoleAuditLog,
+    pub faucet_requests: FaucetRequestMap,
+    // (requester, request id) index for per-user request history
+    pub faucet_requests_by_user: FaucetRequestsByUser,
+    pub faucet_policy: FaucetPolicyCell,
+    // Principals a Moderator vouched for, for faucet policies that require verification
+    pub verified_users: VerifiedUserMap,
+    pub payments: PaymentMap,
+    // (payer, payment id) index for idempotency lookups and per-payer history
+    pub payments_by_payer: PaymentsByPayer,
+    // Keyed by a hash of caller, operation and key
+    pub processed_keys: ProcessedKeyMap,
+    // (first seen, key hash) index for dropping keys once the dedup window has passed
+    pub processed_keys_by_time: ProcessedKeysByTime,
+    // icrc1_decimals of the platform ledger and the talent ledgers, as read from them
+    pub ledger_decimals: LedgerDecimalsMap,
+    // Append-only log of purchases with (buyer, id), (token, id) and (timestamp, id) indexes
+    pub trades: TradeLog,
+    pub trades_by_buyer: TradeIndex,
+    pub trades_by_token: TradeIndex,
+    pub trades_by_time: TradesByTime,
+    // OHLC candles keyed by (token, bucket start)
+    pub hourly_candles: CandleMap,
+    pub daily_candles: CandleMap,
+    pub weekly_candles: CandleMap,
+    // Sort and filter keys of the catalog, see catalog.rs
+    pub catalog_index: CatalogIndex,
+    pub token_stats: TokenStatsMap,
+    // Net talent base units per (token, holder) from purchases and sales
+    pub token_positions: TokenPositionMap,
+    // (owner, token) of every token an owner received or tracks, for portfolio balances
+    pub token_holdings: TokenHoldingsIndex,
+}
+
+impl State {
+    pub fn admin(&self) -> Principal {
+        self.config.get().admin
+    }
+
+    pub fn token_canister_id(&self) -> Principal {
+        self.config.get().token_canister_id
+    }
+
+    // Apply a change to the config and write it back to stable memory
+    pub fn update_config(&mut self, update: impl FnOnce(&mut FactoryConfig)) {
+        let mut config = self.config.get().clone();
+        update(&mut config);
+        self.config.set(config).expect("Failed to persist factory config");
+    }
+
+    // The active ledger WASM version together with its module bytes
+    pub fn active_wasm(&self) -> Option<(WasmVersion, Vec<u8>)> {
+        let version = self.config.get().active_wasm_version.clone()?;
+        let metadata = self.wasm_versions.get(&version)?;
+        let module = self.wasm_modules.get(&version)?;
+        Some((metadata, module))
+    }
+
+    pub fn has_role(&self, principal: Principal, role: Role) -> bool {
+        if principal == self.admin() {
+            return true;
+        }
+        let roles = self.roles.get(&principal).unwrap_or_default();
+        roles.0.contains(&Role::SuperAdmin) || roles.0.contains(&role)
+    }
+
+    pub fn ensure_role(&self, caller: Principal, role: Role) -> Result<(), FactoryError> {
+        if !self.has_role(caller, role) {
+            return Err(FactoryError::MissingRole { caller, role });
+        }
+        Ok(())
+    }
+}
+
+pub fn ensure_role(caller: Principal, role: Role) -> Result<(), FactoryError> {
+    STATE.with(|state| state.borrow().ensure_role(caller, role))
+}
+
+// Held while a call works on a multi-step record (creation job, purchase, redemption), so the same record
+// is never advanced by two calls at once. Released when the call finishes or traps.
+pub struct InFlightGuard((&'static str, u64));
+
+impl InFlightGuard {
+    pub fn acquire(kind: &'static str, id: u64) -> Result<Self, FactoryError> {
+        IN_FLIGHT.with(|in_flight| {
+            if !in_flight.borrow_mut().insert((kind, id)) {
+                return Err(FactoryError::InProgress { operation: kind.to_string(), id });
+            }
+            Ok(Self((kind, id)))
+        })
+    }
+
+    pub fn is_held(kind: &'static str, id: u64) -> bool {
+        IN_FLIGHT.with(|in_flight| in_flight.borrow().contains(&(kind, id)))
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&self.0));
+    }
 }
 
 // State Initialization
 #[ic_cdk::init]
-fn init() {
+fn init(args: FactoryInitArgs) {
+    if args.admin == Principal::anonymous() {
+        ic_cdk::trap("The factory admin cannot be the anonymous principal");
+    }
+
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        state.admin = Principal::from_text("aaaaa-aa").unwrap();
-        state.token_canister_id = ic_cdk::api::id();
-        state.is_admin_registered = false;
+        state.update_config(|config| {
+            config.admin = args.admin;
+            config.token_canister_id = args.token_canister_id.unwrap_or_else(ic_cdk::api::id);
+            config.is_admin_registered = true;
+            config.backend_canister_id = args.backend_canister_id;
+        });
         state.tokens = init_token_map();
-        state.faucet_requests = init_faucet_request_map();
         state.talent_token_map = init_talent_token_map();
-        state.purchase_history = init_purchase_history_map();
+    });
+
+    crate::faucet::schedule_auto_approval();
+    crate::pricing::prime_platform_decimals();
+}
+
+// Everything lives in stable memory, so there is nothing to restore here.
+// We only verify that the config we got back is consistent before accepting the upgrade.
+// Factories installed before the admin came from init arguments take the admin from the
+// upgrade arguments if none was ever registered.
+#[ic_cdk::post_upgrade]
+fn post_upgrade(args: Option<FactoryInitArgs>) {
+    migrate_legacy_wasm_module();
+    migrate_legacy_faucet_requests();
+    migrate_legacy_purchase_history();
+    // After the trade migration, so migrated trades are charted too
+    backfill_price_history();
+    backfill_catalog();
+    backfill_token_holdings();
+    backfill_wasm_kinds();
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+
+        if !state.config.get().is_admin_registered {
+            let Some(args) = &args else {
+                ic_cdk::trap("No admin is registered; pass FactoryInitArgs with the upgrade");
+            };
+            state.update_config(|config| {
+                config.admin = args.admin;
+                config.is_admin_registered = true;
+                if let Some(token_canister_id) = args.token_canister_id {
+                    config.token_canister_id = token_canister_id;
+                }
+            });
+        }
+        if let Some(backend_canister_id) = args.as_ref().and_then(|args| args.backend_canister_id) {
+            state.update_config(|config| config.backend_canister_id = Some(backend_canister_id));
+        }
+
+        let config = state.config.get();
+        if config.admin == Principal::anonymous() {
+            ic_cdk::trap("Restored config has the admin set to the anonymous principal");
+        }
+
+        if let Some(version) = &config.active_wasm_version {
+            if !state.wasm_versions.contains_key(version) || !state.wasm_modules.contains_key(version) {
+                ic_cdk::trap(&format!("Active WASM version {} was not restored", version));
+            }
+        } else if !state.tokens.is_empty() {
+            ic_cdk::println!("Warning: talent tokens exist but no active WASM version is set");
+        }
+
+        ic_cdk::println!(
+            "Factory state restored: admin {}, token canister {}, {} talent tokens, {} WASM versions (active: {:?})",
+            config.admin,
+            config.token_canister_id,
+            state.tokens.len(),
+            state.wasm_versions.len(),
+            config.active_wasm_version
+        );
+    });
+
+    // Timers do not survive an upgrade, so pick up any run that was still in progress
+    crate::ledger_upgrade::reschedule_running_upgrades();
+    crate::faucet::schedule_auto_approval();
+    crate::pricing::prime_platform_decimals();
+}
+
+// Turn the old one-entry-per-user faucet map into per-request records, with a zero timestamp
+// as the old map kept none. Pending requests carry over as they are; tokens already given
+// become one paid record per user so lifetime totals stay correct.
+fn migrate_legacy_faucet_requests() {
+    let mut legacy = LegacyFaucetRequestMap::init(get_legacy_faucet_request_map_memory());
+    if legacy.is_empty() {
+        return;
+    }
+
+    let entries: Vec<_> = legacy.iter().collect();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        for (user, request) in entries {
+            if request.total_token_given > 0 {
+                crate::faucet::insert_request(&mut state, user, None, Nat::from(request.total_token_given), 0, FaucetStatus::Paid);
+            }
+            if request.status == "pending" {
+                crate::faucet::insert_request(&mut state, user, None, Nat::from(request.current_token_request), 0, FaucetStatus::Pending);
+            }
+        }
+    });
+    legacy.clear_new();
+}
+
+// Build the candles from the trades and sales made before prices were kept
+fn backfill_price_history() {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if !state.hourly_candles.is_empty() {
+            return;
+        }
+
+        let mut fills: Vec<_> = state.trades.values()
+            .filter_map(|trade| Some((trade.timestamp, trade.token, trade.quantity?, trade.total_paid?)))
+            .collect();
+        fills.extend(state.redemptions.values()
+            .filter(|redemption| redemption.burn_block.is_some())
+            .map(|redemption| (redemption.created_at, redemption.token, redemption.quantity, redemption.payout)));
+
+        fills.sort_by_key(|(timestamp, ..)| *timestamp);
+        for (timestamp, token, quantity, amount) in fills {
+            crate::market::record_fill(&mut state, token, timestamp, &quantity, &amount);
+        }
+    });
+}
+
+// Index the tokens registered before the catalog, from the trades, sales and candles kept so far
+fn backfill_catalog() {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.catalog_index.is_empty() && !state.tokens.is_empty() {
+            crate::catalog::rebuild(&mut state);
+        }
+    });
+}
+
+// Portfolios list every token someone was minted or bought, including for another account
+fn backfill_token_holdings() {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if !state.token_holdings.is_empty() {
+            return;
+        }
+        let holdings: Vec<(Principal, Principal)> = state.token_positions.keys().collect();
+        for (token, holder) in holdings {
+            state.token_holdings.insert((holder, token), ());
+        }
+    });
+}
+
+// Versions registered before kinds were recorded are ledgers unless something installed or
+// configured them as an index WASM
+fn backfill_wasm_kinds() {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let mut index_versions: BTreeSet<String> = state.tokens.values()
+            .filter_map(|metadata| metadata.index_wasm_version)
+            .collect();
+        index_versions.extend(state.ledger_upgrade_runs.values().filter_map(|run| run.index_wasm_version));
+        index_versions.extend(state.config.get().active_index_wasm_version.clone());
+        let untyped: Vec<WasmVersion> = state.wasm_versions.values()
+            .filter(|version| version.kind.is_none())
+            .collect();
+        for mut version in untyped {
+            version.kind = Some(if index_versions.contains(&version.version) { WasmKind::Index } else { WasmKind::Ledger });
+            state.wasm_versions.insert(version.version.clone(), version);
+        }
+    });
+}
+
+// Turn the old per-buyer token lists into trades. Purchase records of the buyer and token
+// fill in the amounts and blocks; pairs bought before purchase records existed become one
+// trade without amounts and with a zero timestamp.
+fn migrate_legacy_purchase_history() {
+    let mut legacy = LegacyPurchaseHistoryMap::init(get_legacy_purchase_history_map_memory());
+    if legacy.is_empty() {
+        return;
+    }
+
+    let entries: Vec<_> = legacy.iter().collect();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let mut trades = Vec::new();
+        for (buyer, tokens) in entries {
+            for token in tokens.0 {
+                let purchased: Vec<_> = state.purchases.values()
+                    .filter(|purchase| purchase.buyer == buyer && purchase.token == token && purchase.mint_block.is_some())
+                    .map(|purchase| crate::trades::trade_from_purchase(&state, &purchase, purchase.created_at))
+                    .collect();
+                if purchased.is_empty() {
+                    trades.push(Trade {
+                        id: 0,
+                        buyer,
+                        token,
+                        quantity: None,
+                        unit_price: None,
+                        total_paid: None,
+                        payment_block: None,
+                        mint_block: None,
+                        timestamp: 0,
+                        purchase_id: None,
+                    });
+                }
+                trades.extend(purchased);
+            }
+        }
+
+        trades.sort_by_key(|trade| trade.timestamp);
+        for trade in trades {
+            crate::trades::insert_trade(&mut state, trade);
+        }
+    });
+    legacy.clear_new();
+}
+
+// Move a WASM uploaded through the old single-blob cell into the version registry
+fn migrate_legacy_wasm_module() {
+    let mut legacy = WasmModuleCell::init(get_wasm_module_cell_memory(), Vec::new())
+        .expect("Failed to initialize legacy WASM module cell");
+    if legacy.get().is_empty() {
+        return;
+    }
+
+    let module = legacy.set(Vec::new()).expect("Failed to clear legacy WASM module cell");
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if !state.wasm_versions.is_empty() {
+            return;
+        }
+
+        let version = WasmVersion {
+            version: LEGACY_WASM_VERSION.to_string(),
+            sha256: crate::wasm_store::sha256_hex(&module),
+            size: module.len() as u64,
+            uploaded_at: ic_cdk::api::time(),
+            uploaded_by: state.admin(),
+            kind: Some(WasmKind::Ledger),
+        };
+        state.wasm_modules.insert(version.version.clone(), module);
+        state.wasm_versions.insert(version.version.clone(), version);
+        state.update_config(|config| config.active_wasm_version = Some(LEGACY_WASM_VERSION.to_string()));
     });
 }
 
@@ -73,39 +533,37 @@ pub fn init_token_map() -> TokenMap {
     TokenMap::init(get_token_map_memory())
 }
 
-pub fn init_faucet_request_map() -> FaucetRequestMap {
-    FaucetRequestMap::init(get_faucet_request_map_memory())
-}
 
 pub fn init_talent_token_map() -> TalentTokenMap {
     TalentTokenMap::init(get_talent_token_map_memory())
 }
 
-pub fn init_purchase_history_map() -> PurchaseHistoryMap {
-    PurchaseHistoryMap::init(get_purchase_history_map_memory())
-}
 
 // Memory accessors for Maps
 pub fn get_token_map_memory() -> Memory {
     MEMORY_MANAGER.with(|m| m.borrow().get(TOKEN_MAP_MEMORY_ID))
 }
 
-pub fn get_faucet_request_map_memory() -> Memory {
-    MEMORY_MANAGER.with(|m| m.borrow().get(FAUCET_REQUEST_MAP_MEMORY_ID))
+pub fn get_legacy_faucet_request_map_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(LEGACY_FAUCET_REQUEST_MAP_MEMORY_ID))
 }
 
 pub fn get_talent_token_map_memory() -> Memory {
     MEMORY_MANAGER.with(|m| m.borrow().get(TALENT_TOKEN_MAP_MEMORY_ID))
 }
 
-pub fn get_purchase_history_map_memory() -> Memory {
-    MEMORY_MANAGER.with(|m| m.borrow().get(PURCHASE_HISTORY_MAP_MEMORY_ID))
+pub fn get_legacy_purchase_history_map_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(LEGACY_PURCHASE_HISTORY_MAP_MEMORY_ID))
+}
+
+pub fn get_wasm_module_cell_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(WASM_MODULE_CELL_MEMORY_ID))
 }
 
 
 // Implement Storable for TokenMetadata
 impl Storable for TokenMetadata {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
@@ -119,7 +577,170 @@ impl Storable for TokenMetadata {
 
 // Implement Storable for FaucetTokenRequest
 impl Storable for FaucetTokenRequest {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+// Implement Storable for FactoryConfig
+impl Storable for FactoryConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+// Implement Storable for WasmVersion
+impl Storable for WasmVersion {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+// Implement Storable for WasmUpload
+impl Storable for WasmUpload {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+// Implement Storable for LedgerUpgradeRun
+impl Storable for LedgerUpgradeRun {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+// Implement Storable for CreationJob
+impl Storable for CreationJob {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+// Implement Storable for Purchase
+impl Storable for Purchase {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+// Implement Storable for Redemption
+impl Storable for Redemption {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Storable for Trade {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Storable for Candle {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Storable for TokenStats {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Storable for ProcessedKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Storable for Payment {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
@@ -133,7 +754,7 @@ impl Storable for FaucetTokenRequest {
 
 // Implement Storable for the wrapper instead
 impl Storable for PrincipalVec {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(&self.0).unwrap())
     }
 
@@ -143,4 +764,69 @@ impl Storable for PrincipalVec {
 
     const BOUND: ic_stable_structures::storable::Bound = 
         ic_stable_structures::storable::Bound::Unbounded;
-}
\ No newline at end of file
+}
+
+impl Storable for RoleSet {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Storable for RoleAuditEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Storable for FaucetRequest {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Storable for VerifiedUser {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Storable for FaucetPolicy {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
@@ -0,0 +1,407 @@
+use candid::{Encode, Nat, Principal};
+use crate::catalog;
+use crate::ledger::{self, CREATION_ESCROW_SUBACCOUNT};
+use crate::state_handler::{ensure_role, InFlightGuard, STATE};
+use crate::types::*;
+use ic_cdk::api::caller;
+use ic_cdk::api::management_canister::main::{
+    CanisterIdRecord, CanisterInstallMode, CanisterSettings, CreateCanisterArgument, InstallCodeArgument,
+};
+use icrc_ledger_types::icrc::generic_value::Value;
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
+
+// Cycles attached to create_canister for every talent token ledger and its index
+const LEDGER_CREATION_CYCLES: u128 = 1_000_000_000_000;
+const INDEX_CREATION_CYCLES: u128 = 1_000_000_000_000;
+// How often the index pulls new blocks from its ledger
+const INDEX_SYNC_INTERVAL_SECONDS: u64 = 10;
+
+pub const CREATION_JOB: &str = "Creation job";
+
+fn dedup(job: &CreationJob, step: &str) -> ledger::Dedup {
+    ledger::Dedup::new(&format!("creation/{}/{}", job.id, step), job.created_at)
+}
+
+fn is_terminal(status: &CreationJobStatus) -> bool {
+    matches!(status, CreationJobStatus::Completed | CreationJobStatus::RolledBack)
+}
+
+fn get_job(job_id: u64) -> Result<CreationJob, FactoryError> {
+    STATE.with(|state| {
+        state.borrow().creation_jobs.get(&job_id)
+            .ok_or(FactoryError::CreationJobNotFound(job_id))
+    })
+}
+
+fn save_job(mut job: CreationJob) {
+    job.updated_at = ic_cdk::api::time();
+    STATE.with(|state| {
+        state.borrow_mut().creation_jobs.insert(job.id, job);
+    });
+}
+
+pub fn open_job_for(creator: Principal) -> Option<CreationJob> {
+    STATE.with(|state| {
+        state.borrow().creation_jobs.values()
+            .find(|job| job.creator == creator && !is_terminal(&job.status))
+    })
+}
+
+pub fn create_job(creator: Principal, args: CreateTokenArgs, fee: Nat, fee_subaccount: Option<Subaccount>, wasm_version: String, index_wasm_version: Option<String>) -> u64 {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let job_id = state.creation_jobs.last_key_value().map(|(id, _)| id + 1).unwrap_or(0);
+        let now = ic_cdk::api::time();
+        state.creation_jobs.insert(job_id, CreationJob {
+            id: job_id,
+            creator,
+            args,
+            fee,
+            wasm_version,
+            status: CreationJobStatus::Pending,
+            payment_block: None,
+            canister_id: None,
+            settlement_block: None,
+            last_error: None,
+            created_at: now,
+            updated_at: now,
+            fee_subaccount,
+            index_wasm_version,
+            index_canister_id: None,
+        });
+        job_id
+    })
+}
+
+// Run the job forward from its last completed step until it is completed or a step fails
+pub async fn advance_job(job_id: u64) -> Result<Principal, FactoryError> {
+    let _guard = InFlightGuard::acquire(CREATION_JOB, job_id)?;
+
+    loop {
+        let mut job = get_job(job_id)?;
+        let step = match job.status {
+            CreationJobStatus::Pending => charge_fee(&mut job).await,
+            CreationJobStatus::Charged => create_canister(&mut job).await,
+            CreationJobStatus::CanisterCreated => install_ledger(&mut job).await,
+            CreationJobStatus::CodeInstalled if job.index_wasm_version.is_some() => create_index_canister(&mut job).await,
+            CreationJobStatus::CodeInstalled => register_token(&mut job),
+            CreationJobStatus::IndexCreated => install_index(&mut job).await,
+            CreationJobStatus::IndexInstalled => register_token(&mut job),
+            CreationJobStatus::Registered => release_fee(&mut job).await,
+            CreationJobStatus::Completed => {
+                return job.canister_id
+                    .ok_or_else(|| FactoryError::InvalidState(format!("Creation job {} has no canister", job_id)));
+            }
+            CreationJobStatus::RolledBack => {
+                return Err(FactoryError::InvalidState(format!("Creation job {} was rolled back", job_id)));
+            }
+        };
+
+        if let Err(e) = step {
+            job.last_error = Some(e.to_string());
+            save_job(job);
+            return Err(e);
+        }
+        job.last_error = None;
+        save_job(job);
+    }
+}
+
+// Undo a job that has not registered its token yet: delete its canisters and refund the fee
+pub async fn roll_back_job(job_id: u64) -> Result<CreationJob, FactoryError> {
+    let _guard = InFlightGuard::acquire(CREATION_JOB, job_id)?;
+    let mut job = get_job(job_id)?;
+
+    match job.status {
+        CreationJobStatus::Registered | CreationJobStatus::Completed => {
+            return Err(FactoryError::InvalidState(format!("Creation job {} already registered its token and cannot be rolled back", job_id)));
+        }
+        CreationJobStatus::RolledBack => return Ok(job),
+        _ => {}
+    }
+
+    if let Err(e) = undo_job(&mut job).await {
+        job.last_error = Some(e.to_string());
+        save_job(job);
+        return Err(e);
+    }
+    save_job(job.clone());
+    Ok(job)
+}
+
+async fn undo_job(job: &mut CreationJob) -> Result<(), FactoryError> {
+    if let Some(index_canister_id) = job.index_canister_id {
+        delete_canister(index_canister_id).await?;
+        // Back to the step before the index, so a job whose ledger cannot be deleted still resumes cleanly
+        job.index_canister_id = None;
+        job.status = CreationJobStatus::CodeInstalled;
+    }
+    if let Some(canister_id) = job.canister_id {
+        delete_canister(canister_id).await?;
+        job.canister_id = None;
+        job.status = CreationJobStatus::Charged;
+    }
+
+    if job.payment_block.is_some() && job.settlement_block.is_none() {
+        let ledger = STATE.with(|state| state.borrow().token_canister_id());
+        let refund_block = ledger::release(ledger, CREATION_ESCROW_SUBACCOUNT, job.fee_account(), job.fee.clone(), dedup(job, "refund")).await?;
+        job.settlement_block = refund_block;
+    }
+
+    job.status = CreationJobStatus::RolledBack;
+    job.last_error = None;
+    Ok(())
+}
+
+async fn charge_fee(job: &mut CreationJob) -> Result<(), FactoryError> {
+    let ledger = STATE.with(|state| state.borrow().token_canister_id());
+    let payment_block = ledger::transfer_from(
+        ledger,
+        job.fee_account(),
+        ledger::factory_account(CREATION_ESCROW_SUBACCOUNT),
+        job.fee.clone(),
+        dedup(job, "fee"),
+    )
+    .await?;
+
+    job.payment_block = Some(payment_block);
+    job.status = CreationJobStatus::Charged;
+    Ok(())
+}
+
+// A canister controlled by the factory and the token creator
+async fn new_canister(creator: Principal, cycles: u128) -> Result<Principal, FactoryError> {
+    let settings = CanisterSettings {
+        controllers: Some(vec![ic_cdk::id(), creator]),
+        compute_allocation: None,
+        memory_allocation: None,
+        freezing_threshold: None,
+        log_visibility: None,
+        reserved_cycles_limit: None,
+        wasm_memory_limit: None,
+    };
+
+    let create_args = CreateCanisterArgument {
+        settings: Some(settings)
+    };
+
+    let (canister_id,): (CanisterIdRecord,) = ic_cdk::api::call::call_with_payment128(
+        Principal::management_canister(),
+        "create_canister",
+        (create_args,),
+        cycles
+    ).await.map_err(|e| ledger::call_failed(Principal::management_canister(), "create_canister", e))?;
+
+    Ok(canister_id.canister_id)
+}
+
+async fn create_canister(job: &mut CreationJob) -> Result<(), FactoryError> {
+    job.canister_id = Some(new_canister(job.creator, LEDGER_CREATION_CYCLES).await?);
+    job.status = CreationJobStatus::CanisterCreated;
+    Ok(())
+}
+
+async fn create_index_canister(job: &mut CreationJob) -> Result<(), FactoryError> {
+    job.index_canister_id = Some(new_canister(job.creator, INDEX_CREATION_CYCLES).await?);
+    job.status = CreationJobStatus::IndexCreated;
+    Ok(())
+}
+
+async fn install_ledger(job: &mut CreationJob) -> Result<(), FactoryError> {
+    let canister_id = job.canister_id
+        .ok_or_else(|| FactoryError::InvalidState(format!("Creation job {} has no canister", job.id)))?;
+    let wasm_module = STATE.with(|state| state.borrow().wasm_modules.get(&job.wasm_version))
+        .ok_or_else(|| FactoryError::WasmVersionNotFound(job.wasm_version.clone()))?;
+    let token_args = &job.args;
+    let token_creator = job.creator;
+
+    let init_args = InitArgs {
+        minting_account: Account {
+            owner: ic_cdk::id(),
+            subaccount: None,
+        },
+        fee_collector_account: Some(Account {
+            owner: token_creator,
+            subaccount: None,
+        }),
+        transfer_fee: Nat::from(0u64),
+        token_symbol: token_args.symbol.clone(),
+        token_name: token_args.name.clone(),
+        metadata: vec![
+            ("icrc1:name".to_string(), Value::Text(token_args.name.clone())),
+            ("icrc1:symbol".to_string(), Value::Text(token_args.symbol.clone())),
+            ("icrc1:decimals".to_string(), Value::Nat(Nat::from(token_args.decimals as u64))),
+        ],
+        initial_balances: vec![(
+            Account {
+                owner: ic_cdk::id(),
+                subaccount: None,
+            },
+            Nat::from(0u64),
+        )],
+        feature_flags: Some(FeatureFlags {
+            icrc2: true,
+        }),
+        maximum_number_of_accounts: Some(1_000_000),
+        accounts_overflow_trim_quantity: Some(100_000),
+        archive_options: ArchiveOptions {
+            num_blocks_to_archive: 2000,
+            trigger_threshold: 1000,
+            max_message_size_bytes: Some(1024 * 1024),
+            cycles_for_archive_creation: Some(10_000_000_000_000),
+            node_max_memory_size_bytes: Some(3 * 1024 * 1024 * 1024),
+            controller_id: ic_cdk::id(),
+            more_controller_ids: Some(vec![token_creator]),
+            max_transactions_per_response: Some(100),
+        },
+        decimals: Some(token_args.decimals),
+        max_memo_length: Some(256),
+    };
+    let token = LedgerArg::Init(init_args);
+    let serialized_args = Encode!(&token)
+        .map_err(|e| FactoryError::InvalidArgument(format!("Serialization failed: {:?}", e)))?;
+
+    let install_config = InstallCodeArgument {
+        mode: CanisterInstallMode::Install,
+        canister_id,
+        wasm_module,
+        arg: serialized_args
+    };
+
+    let _: () = ic_cdk::api::call::call(
+        Principal::management_canister(),
+        "install_code",
+        (install_config,)
+    ).await.map_err(|e| ledger::call_failed(Principal::management_canister(), "install_code", e))?;
+
+    job.status = CreationJobStatus::CodeInstalled;
+    Ok(())
+}
+
+async fn install_index(job: &mut CreationJob) -> Result<(), FactoryError> {
+    let (Some(ledger_id), Some(index_canister_id), Some(index_wasm_version)) = (job.canister_id, job.index_canister_id, job.index_wasm_version.clone()) else {
+        return Err(FactoryError::InvalidState(format!("Creation job {} has no index canister", job.id)));
+    };
+    let wasm_module = STATE.with(|state| state.borrow().wasm_modules.get(&index_wasm_version))
+        .ok_or(FactoryError::WasmVersionNotFound(index_wasm_version))?;
+
+    let index_arg = Some(IndexArg::Init(IndexInitArgs {
+        ledger_id,
+        retrieve_blocks_from_ledger_interval_seconds: Some(INDEX_SYNC_INTERVAL_SECONDS),
+    }));
+    let arg = Encode!(&index_arg)
+        .map_err(|e| FactoryError::InvalidArgument(format!("Serialization failed: {:?}", e)))?;
+
+    let install_config = InstallCodeArgument {
+        mode: CanisterInstallMode::Install,
+        canister_id: index_canister_id,
+        wasm_module,
+        arg,
+    };
+
+    let _: () = ic_cdk::api::call::call(
+        Principal::management_canister(),
+        "install_code",
+        (install_config,)
+    ).await.map_err(|e| ledger::call_failed(Principal::management_canister(), "install_code", e))?;
+
+    job.status = CreationJobStatus::IndexInstalled;
+    Ok(())
+}
+
+fn register_token(job: &mut CreationJob) -> Result<(), FactoryError> {
+    let canister_id = job.canister_id
+        .ok_or_else(|| FactoryError::InvalidState(format!("Creation job {} has no canister", job.id)))?;
+    let token_args = job.args.clone();
+
+    let metadata = TokenMetadata {
+        name: token_args.name,
+        symbol: token_args.symbol,
+        decimals: token_args.decimals,
+        token_price: token_args.token_price,
+        owner: job.creator,
+        logo: token_args.logo,
+        created: ic_cdk::api::time(),
+        wasm_version: Some(job.wasm_version.clone()),
+        pricing: token_args.pricing,
+        tags: token_args.tags,
+        index_canister_id: job.index_canister_id,
+        index_wasm_version: job.index_canister_id.and(job.index_wasm_version.clone()),
+    };
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.tokens.insert(canister_id, metadata);
+        state.talent_token_map.insert(job.creator, canister_id);
+        // A fresh ledger starts with nothing minted
+        state.token_supply.insert(canister_id, 0);
+        catalog::refresh_price(&mut state, canister_id);
+    });
+
+    job.status = CreationJobStatus::Registered;
+    Ok(())
+}
+
+// The fee stays in escrow until the token is registered, then goes to the admin
+async fn release_fee(job: &mut CreationJob) -> Result<(), FactoryError> {
+    let (ledger, admin) = STATE.with(|state| {
+        let state = state.borrow();
+        (state.token_canister_id(), state.admin())
+    });
+    let release_block = ledger::release(ledger, CREATION_ESCROW_SUBACCOUNT, Account::from(admin), job.fee.clone(), dedup(job, "release")).await?;
+
+    job.settlement_block = release_block;
+    job.status = CreationJobStatus::Completed;
+    Ok(())
+}
+
+async fn delete_canister(canister_id: Principal) -> Result<(), FactoryError> {
+    let record = CanisterIdRecord { canister_id };
+
+    let _: () = ic_cdk::api::call::call(
+        Principal::management_canister(),
+        "stop_canister",
+        (record,)
+    ).await.map_err(|e| ledger::call_failed(Principal::management_canister(), "stop_canister", e))?;
+
+    let _: () = ic_cdk::api::call::call(
+        Principal::management_canister(),
+        "delete_canister",
+        (record,)
+    ).await.map_err(|e| ledger::call_failed(Principal::management_canister(), "delete_canister", e))?;
+
+    Ok(())
+}
+
+// Jobs that stopped on an error and are not being processed right now
+#[ic_cdk::query]
+pub fn get_stuck_creation_jobs() -> Result<Vec<CreationJob>, FactoryError> {
+    ensure_role(caller(), Role::Treasurer)?;
+
+    STATE.with(|state| {
+        Ok(state.borrow().creation_jobs.values()
+            .filter(|job| !is_terminal(&job.status) && job.last_error.is_some() && !InFlightGuard::is_held(CREATION_JOB, job.id))
+            .collect())
+    })
+}
+
+#[ic_cdk::query]
+pub fn get_creation_job(job_id: u64) -> Result<CreationJob, FactoryError> {
+    let job = get_job(job_id)?;
+    if job.creator != caller() {
+        ensure_role(caller(), Role::Moderator)?;
+    }
+    Ok(job)
+}
+
+#[ic_cdk::update]
+pub async fn resume_creation_job(job_id: u64) -> Result<Principal, FactoryError> {
+    ensure_role(caller(), Role::Treasurer)?;
+    advance_job(job_id).await
+}
+
+#[ic_cdk::update]
+pub async fn roll_back_creation_job(job_id: u64) -> Result<CreationJob, FactoryError> {
+    ensure_role(caller(), Role::Treasurer)?;
+    roll_back_job(job_id).await
+}
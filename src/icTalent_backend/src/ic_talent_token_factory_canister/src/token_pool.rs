@@ -1,248 +1,166 @@
-use candid::{Nat, Principal, Encode};
+use candid::{Nat, Principal};
 use crate::types::*;
-use ic_cdk::api::management_canister::main::{
-    CreateCanisterArgument, CanisterIdRecord, CanisterSettings, CanisterInstallMode, InstallCodeArgument,
-};
-use crate::api_update::transfer_tokens;
-use crate::state_handler::{STATE, WASM_MODULE};
+use crate::state_handler::STATE;
+use crate::ledger;
+use crate::token_creation;
+use crate::purchases;
+use crate::pricing;
+use crate::redemptions;
+use crate::idempotency;
+use crate::catalog;
 use ic_cdk::api::caller;
-use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
 
-use icrc_ledger_types::icrc::generic_value::Value;
-use icrc_ledger_types::icrc1::transfer::{TransferArg, TransferError, BlockIndex};
+const CREATION_FEE_TOKENS: u32 = 100;
 
-use hex;
-
-
-
-
-#[ic_cdk::update]
-pub async fn update_wasm_module(wasm: Vec<u8>) -> Result<String, String> {
-    // Check if caller is admin
-    STATE.with(|state| {
-        let state = state.borrow();
-        if state.admin != caller() {
-            return Err("Unauthorized: Only admin can update WASM module".to_string());
-        }
-        Ok(())
-    })?;
-
-    // Update WASM module
-    WASM_MODULE.with(|module| {
-        let mut module = module.borrow_mut();
-        *module = wasm;
-    });
-
-    Ok("WASM module updated successfully.".to_string())
+// Charge 100 whole platform tokens for token creation
+pub async fn creation_fee() -> Result<Nat, FactoryError> {
+    let platform = STATE.with(|state| state.borrow().token_canister_id());
+    Ok(Nat::from(CREATION_FEE_TOKENS) * pricing::unit(ledger::decimals(platform).await?))
 }
 
+// The creator has no token yet and no creation job underway
+fn ensure_can_create(creator: Principal) -> Result<(), FactoryError> {
+    if let Some(token) = STATE.with(|state| state.borrow().talent_token_map.get(&creator)) {
+        return Err(FactoryError::AlreadyHasToken(token));
+    }
+    if let Some(job) = token_creation::open_job_for(creator) {
+        return Err(FactoryError::InProgress { operation: "Creation job".to_string(), id: job.id });
+    }
+    Ok(())
+}
 
+// A retry with the same idempotency key picks up the first call's creation job
 #[ic_cdk::update]
-async fn create_talent_token_canister(token_args: CreateTokenArgs) -> Result<Principal, String> {
+async fn create_talent_token_canister(mut token_args: CreateTokenArgs, from_subaccount: Option<Subaccount>, idempotency_key: Option<String>) -> Result<Principal, FactoryError> {
     let token_creator = caller();
-    
-    // Check if user has already created a token
-    STATE.with(|state| {
-        let state = state.borrow();
-        if state.talent_token_map.contains_key(&token_creator) {
-            return Err("You can only create one token".to_string());
-        }
-        Ok(())
-    })?;
-
-    // Charge 100 tokens for token creation
-    let token_charge = 100u32;
 
-    
-    // Transfer tokens from creator to factory canister
-    let transfer_result = transfer_tokens(STATE.with(|state| state.borrow().admin), token_charge).await;
-    if let Err(e) = transfer_result {
-        return Err(format!("Failed to charge tokens: {}", e));
-    }
-
-    // Rest of the existing create_talent_token_canister code...
-    WASM_MODULE.with(|wasm| {
-        if wasm.borrow().is_empty() {
-            return Err("WASM module not set".to_string());
+    if let Some(key) = &idempotency_key {
+        idempotency::validate_key(key)?;
+        let processed = STATE.with(|state| idempotency::find(&state.borrow(), token_creator, token_creation::CREATION_JOB, key));
+        if let Some(job_id) = processed.and_then(|processed| processed.record_id) {
+            return token_creation::advance_job(job_id).await;
         }
-        Ok(())
-    })?;
-
-    let settings = CanisterSettings {
-        controllers: Some(vec![ic_cdk::id(), token_creator]),
-        compute_allocation: None,
-        memory_allocation: None,
-        freezing_threshold: None,
-        log_visibility: None,
-        reserved_cycles_limit: None,
-        wasm_memory_limit: None,
-    };
-
-    let create_args = CreateCanisterArgument {
-        settings: Some(settings)
-    };
-
-  
-    let (canister_id,): (CanisterIdRecord,) = ic_cdk::api::call::call_with_payment(
-        Principal::management_canister(),
-        "create_canister",
-        (create_args,),
-        1_000_000_000_000
-    ).await.map_err(|e| format!("Creation failed: {:?}", e))?;
-
-    let wasm_module = WASM_MODULE.with(|w| w.borrow().clone());
-    
-    // Updated initialization args
-    let init_args = InitArgs {
-        minting_account: Account {
-            owner: ic_cdk::id(),
-            subaccount: None,
-        },
-        fee_collector_account: Some(Account {
-            owner: token_creator,
-            subaccount: None,
-        }),
-        transfer_fee: Nat::from(0u64),
-        token_symbol: token_args.symbol.clone(),
-        token_name: token_args.name.clone(),
-        metadata: vec![
-            ("icrc1:name".to_string(), Value::Text(token_args.name.clone())),
-            ("icrc1:symbol".to_string(), Value::Text(token_args.symbol.clone())),
-            ("icrc1:decimals".to_string(), Value::Nat(Nat::from(token_args.decimals as u64))),
-        ],
-        initial_balances: vec![(
-            Account {
-                owner: ic_cdk::id(),
-                subaccount: None,
-            },
-            Nat::from(0u64),
-        )],
-        feature_flags: Some(FeatureFlags {
-            icrc2: true,
-        }),
-        maximum_number_of_accounts: Some(1_000_000),
-        accounts_overflow_trim_quantity: Some(100_000),
-        archive_options: ArchiveOptions {
-            num_blocks_to_archive: 2000,
-            trigger_threshold: 1000,
-            max_message_size_bytes: Some(1024 * 1024),
-            cycles_for_archive_creation: Some(10_000_000_000_000),
-            node_max_memory_size_bytes: Some(3 * 1024 * 1024 * 1024),
-            controller_id: ic_cdk::id(),
-            more_controller_ids: Some(vec![token_creator]),
-            max_transactions_per_response: Some(100),
-        },
-        decimals: Some(token_args.decimals as u8),
-        max_memo_length: Some(256),
-    };
-    let token = LedgerArg::Init(init_args);
-    let serialized_args = Encode!(&token).expect("Serialization failed");
-    // Debug print the hex representation of serialized args
-    ic_cdk::println!("Serialized args (hex): {}", hex::encode(&serialized_args));
-
-    let install_config = InstallCodeArgument {
-        mode: CanisterInstallMode::Install,
-        canister_id: canister_id.canister_id,
-        wasm_module,
-        arg: serialized_args
-    };
+    }
+    ensure_can_create(token_creator)?;
 
-    let _: () = ic_cdk::api::call::call(
-        Principal::management_canister(),
-        "install_code",
-        (install_config,)
-    ).await.map_err(|e| format!("Creation failed: {:?}", e))?;
+    if let Some(pricing) = &token_args.pricing {
+        pricing::validate_pricing(pricing)?;
+    }
+    if let Some(tags) = token_args.tags.take() {
+        token_args.tags = Some(catalog::normalize_tags(tags)?);
+    }
 
-    let metadata = TokenMetadata {
-        name: token_args.name,
-        symbol: token_args.symbol,
-        decimals: token_args.decimals,
-        token_price: token_args.token_price,
-        owner: token_creator,
-        logo: token_args.logo,
-        created: ic_cdk::api::time(),
-    };
+    // Make sure a ledger WASM is available before charging anything
+    let (wasm_version, _) = STATE.with(|state| state.borrow().active_wasm())
+        .ok_or(FactoryError::NoActiveWasmVersion)?;
+    let index_wasm_version = STATE.with(|state| state.borrow().config.get().active_index_wasm_version.clone());
 
-    STATE.with(|state| {
-        state.borrow_mut().tokens.insert(canister_id.canister_id, metadata);
-    });
+    let platform = STATE.with(|state| state.borrow().token_canister_id());
+    let token_charge = creation_fee().await?;
+    ledger::ensure_allowance(platform, Account { owner: token_creator, subaccount: from_subaccount }, &token_charge).await?;
 
-    STATE.with(|state| {
-        state.borrow_mut().talent_token_map.insert(token_creator, canister_id.canister_id);
+    // Check again and record the job in the same step, so a concurrent call or a retry that
+    // arrived while the allowance was read cannot start a second creation
+    let retried_job = STATE.with(|state| {
+        let state = state.borrow();
+        idempotency_key.as_ref()
+            .and_then(|key| idempotency::find(&state, token_creator, token_creation::CREATION_JOB, key))
+            .and_then(|processed| processed.record_id)
     });
-
-    Ok(canister_id.canister_id)
+    if let Some(job_id) = retried_job {
+        return token_creation::advance_job(job_id).await;
+    }
+    ensure_can_create(token_creator)?;
+    let job_id = token_creation::create_job(token_creator, token_args, token_charge, from_subaccount, wasm_version.version, index_wasm_version);
+    if let Some(key) = &idempotency_key {
+        STATE.with(|state| idempotency::remember(&mut state.borrow_mut(), token_creator, token_creation::CREATION_JOB, key, Some(job_id)));
+    }
+    match token_creation::advance_job(job_id).await {
+        Ok(canister_id) => Ok(canister_id),
+        Err(e) => {
+            let job = STATE.with(|state| state.borrow().creation_jobs.get(&job_id));
+            match job {
+                // The token is live, only the fee release is left for an admin to retry
+                Some(job) if job.status == CreationJobStatus::Registered => {
+                    job.canister_id.ok_or(e)
+                }
+                _ => {
+                    // Undo what was done so far; if that fails too the job stays for an admin
+                    match token_creation::roll_back_job(job_id).await {
+                        Ok(_) => Err(FactoryError::RolledBack(Box::new(e))),
+                        Err(rollback_error) => {
+                            ic_cdk::println!("Rollback of creation job {} failed: {}", job_id, rollback_error);
+                            Err(e)
+                        }
+                    }
+                }
+            }
+        }
+    }
 }
 
 
+// `quantity` is in talent base units. Pays from `from_subaccount` of the caller and mints to `to`, the caller's default account if not given.
+// A retry with the same idempotency key resumes the first call's purchase instead of buying again.
 #[ic_cdk::update]
-pub async fn buy_talent_token(canister_id: Principal, quantity: u32) -> Result<String, String> {
-    let buyer = caller();
+pub async fn buy_talent_token(canister_id: Principal, quantity: Nat, from_subaccount: Option<Subaccount>, to: Option<Account>, idempotency_key: Option<String>) -> Result<String, FactoryError> {
+    let payer = Account { owner: caller(), subaccount: from_subaccount };
+
+    if let Some(key) = &idempotency_key {
+        idempotency::validate_key(key)?;
+        let processed = STATE.with(|state| idempotency::find(&state.borrow(), payer.owner, purchases::PURCHASE, key));
+        if let Some(purchase_id) = processed.and_then(|processed| processed.record_id) {
+            purchases::advance_purchase(purchase_id).await?;
+            return Ok("Token purchase successful".to_string());
+        }
+    }
     
     // Get token metadata
     let token_metadata = STATE.with(|state| {
         state.borrow().tokens.get(&canister_id)
-            .ok_or_else(|| "Token not found".to_string())
+            .ok_or(FactoryError::TokenNotFound(canister_id))
     })?;
     
-    // Calculate total cost (token_price * quantity)
-    let total_cost = (token_metadata.token_price as u32) * quantity;
-    
-    // Transfer tokens from buyer to token owner
-    let transfer_result = transfer_tokens(token_metadata.owner, total_cost).await;
-    if let Err(e) = transfer_result {
-        return Err(format!("Failed to transfer payment: {}", e));
-    }
+    let (pricing_model, token_decimals) = pricing::pricing_of(canister_id, &token_metadata).await?;
+    let platform = STATE.with(|state| state.borrow().token_canister_id());
+    let expected_cost = pricing::current_purchase_cost(canister_id, &token_metadata, &quantity).await?;
+    ledger::ensure_allowance(platform, payer, &expected_cost).await?;
+
+    // Price against the supply and reserve the tokens in the same step, so concurrent
+    // purchases each pay their own place on the curve
+    let purchase_id = STATE.with(|state| {
+        // A retry may have started the purchase while the supply was fetched
+        if let Some(key) = &idempotency_key {
+            if let Some(purchase_id) = idempotency::find(&state.borrow(), payer.owner, purchases::PURCHASE, key).and_then(|processed| processed.record_id) {
+                return Ok(purchase_id);
+            }
+        }
+        let supply = Nat::from(state.borrow().token_supply.get(&canister_id).unwrap_or_default());
+        let total_cost = pricing::purchase_cost(&pricing_model, &supply, &quantity, token_decimals)?;
+        let reserve_amount = redemptions::reserve_share(&total_cost);
+        pricing::set_supply(canister_id, &(supply + quantity.clone()))?;
+        let purchase_id = purchases::create_purchase(payer, to, canister_id, token_metadata.owner, quantity.clone(), total_cost, reserve_amount);
+        if let Some(key) = &idempotency_key {
+            idempotency::remember(&mut state.borrow_mut(), payer.owner, purchases::PURCHASE, key, Some(purchase_id));
+        }
+        Ok::<_, FactoryError>(purchase_id)
+    })?;
 
-    // Create transfer args for the buying token
-    let transfer_args = TransferArg {
-        from_subaccount: None,
-        to: Account {
-            owner: buyer,
-            subaccount: None,
-        },
-        amount: Nat::from(quantity as u64),
-        fee: None,
-        memo: None,
-        created_at_time: Some(ic_cdk::api::time()),
-    };
+    // Payment is escrowed, the tokens minted, and only then is the owner paid
+    purchases::advance_purchase(purchase_id).await?;
 
-    // Call the token canister to transfer tokens to buyer
-    match ic_cdk::api::call::call::<(TransferArg,), (Result<BlockIndex, TransferError>,)>(canister_id, "icrc1_transfer", (transfer_args,)).await {
-        Ok((result,)) => {
-            match result {
-                Ok(_) => {
-                    // Update purchase history after successful transfer
-                    STATE.with(|state| {
-                        let mut state = state.borrow_mut();
-                        let mut history = state.purchase_history
-                            .get(&buyer)
-                            .map(|h| h.0.clone())
-                            .unwrap_or_default();
-                        
-                        if !history.contains(&canister_id) {
-                            history.push(canister_id);
-                            state.purchase_history.insert(buyer, PrincipalVec(history));
-                        }
-                    });
-                    
-                    Ok("Token purchase successful".to_string())
-                },
-                Err(e) => Err(format!("Token transfer failed: {:?}", e)),
-            }
-        },
-        Err((code, msg)) => Err(format!("Call failed: code={:?}, msg={}", code, msg)),
-    }
+    Ok("Token purchase successful".to_string())
 }
 
 #[ic_cdk::update]
-pub async fn get_total_supply(token_canister_id: Principal) -> Result<Nat, String> {
+pub async fn get_total_supply(token_canister_id: Principal) -> Result<Nat, FactoryError> {
     
     
     // Check if the token exists
     STATE.with(|state| {
         if !state.borrow().tokens.contains_key(&token_canister_id) {
-            return Err("Token not found".to_string());
+            return Err(FactoryError::TokenNotFound(token_canister_id));
         }
         Ok(())
     })?;
@@ -255,45 +173,8 @@ pub async fn get_total_supply(token_canister_id: Principal) -> Result<Nat, Strin
         ()
     ).await {
         Ok((balance,)) => Ok(balance),
-        Err((code, msg)) => Err(format!("Failed to get balance: code={:?}, msg={}", code, msg))
+        Err(e) => Err(ledger::call_failed(token_canister_id, "icrc1_total_supply", e))
     }
 }
 
-// Optional: Get balances for all tokens owned by the user
-#[ic_cdk::update]
-pub async fn get_all_token_balances() -> Result<Vec<(Principal, Nat)>, String> {
-    let caller = ic_cdk::caller();
-    
-    // Get list of tokens purchased by the user
-    let purchased_tokens = STATE.with(|state| {
-        state.borrow()
-            .purchase_history
-            .get(&caller)
-            .map(|h| h.0.clone())
-            .unwrap_or_default()
-    });
-
-    let mut balances = Vec::new();
-    
-    // Create account struct for the caller
-    let account = Account {
-        owner: caller,
-        subaccount: None,
-    };
-
-    // Get balance for each token
-    for token_id in purchased_tokens {
-        match ic_cdk::api::call::call(
-            token_id,
-            "icrc1_balance_of",
-            (account,)
-        ).await {
-            Ok((balance,)) => balances.push((token_id, balance)),
-            Err(_) => continue // Skip tokens that fail to respond
-        }
-    }
-
-    Ok(balances)
-}
-
 
@@ -0,0 +1,143 @@
+use candid::Principal;
+use crate::catalog;
+use crate::ledger;
+use crate::market;
+use crate::pricing;
+use crate::state_handler::{State, TradeIndex, STATE};
+use crate::types::*;
+use std::ops::Bound;
+
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 200;
+
+pub fn token_decimals(state: &State, token: Principal) -> u8 {
+    ledger::cached_decimals(token)
+        .or_else(|| state.tokens.get(&token).map(|metadata| metadata.decimals))
+        .unwrap_or(0)
+}
+
+pub fn trade_from_purchase(state: &State, purchase: &Purchase, timestamp: u64) -> Trade {
+    let token_decimals = token_decimals(state, purchase.token);
+    Trade {
+        id: 0,
+        buyer: purchase.buyer,
+        token: purchase.token,
+        quantity: Some(purchase.quantity.clone()),
+        unit_price: pricing::unit_price(&purchase.total_cost, &purchase.quantity, token_decimals),
+        total_paid: Some(purchase.total_cost.clone()),
+        payment_block: purchase.payment_block.clone(),
+        mint_block: purchase.mint_block.clone(),
+        timestamp,
+        purchase_id: Some(purchase.id),
+    }
+}
+
+// Trades are only ever appended; the id passed in is replaced by the next one in the log.
+// The trade also goes into the token's price history and catalog volume.
+pub fn append_trade(state: &mut State, trade: Trade) -> u64 {
+    if let (Some(quantity), Some(total_paid)) = (&trade.quantity, &trade.total_paid) {
+        market::record_fill(state, trade.token, trade.timestamp, quantity, total_paid);
+        catalog::add_volume(state, trade.token, total_paid);
+    }
+    insert_trade(state, trade)
+}
+
+// Append without touching the price history or catalog, for migrations that rebuild them afterwards
+pub fn insert_trade(state: &mut State, mut trade: Trade) -> u64 {
+    let id = state.trades.last_key_value().map(|(id, _)| id + 1).unwrap_or(0);
+    trade.id = id;
+    state.trades_by_buyer.insert((trade.buyer, id), ());
+    state.trades_by_token.insert((trade.token, id), ());
+    state.trades_by_time.insert((trade.timestamp, id), ());
+    state.trades.insert(id, trade);
+    id
+}
+
+pub fn tokens_bought_by(state: &State, buyer: Principal) -> Vec<Principal> {
+    let mut tokens = Vec::new();
+    for ((_, id), _) in state.trades_by_buyer.range((buyer, 0)..=(buyer, u64::MAX)) {
+        if let Some(trade) = state.trades.get(&id) {
+            if !tokens.contains(&trade.token) {
+                tokens.push(trade.token);
+            }
+        }
+    }
+    tokens
+}
+
+fn page_size(limit: Option<u32>) -> Result<usize, FactoryError> {
+    match limit.unwrap_or(DEFAULT_PAGE_SIZE) {
+        0 => Err(FactoryError::InvalidArgument("Limit must be greater than zero".to_string())),
+        limit if limit > MAX_PAGE_SIZE => Err(FactoryError::InvalidArgument(format!("Limit cannot exceed {}", MAX_PAGE_SIZE))),
+        limit => Ok(limit as usize),
+    }
+}
+
+// Turn ids in log order into a page, reading one id past the page for the next cursor
+fn to_page(state: &State, ids: impl Iterator<Item = u64>, size: usize) -> TradePage {
+    let mut ids: Vec<u64> = ids.take(size + 1).collect();
+    let next_cursor = if ids.len() > size { ids.pop() } else { None };
+    TradePage {
+        trades: ids.into_iter().filter_map(|id| state.trades.get(&id)).collect(),
+        next_cursor,
+    }
+}
+
+fn by_principal(index: &TradeIndex, principal: Principal, cursor: Option<u64>, size: usize) -> Vec<u64> {
+    index.range((principal, cursor.unwrap_or(0))..=(principal, u64::MAX))
+        .map(|((_, id), _)| id)
+        .take(size + 1)
+        .collect()
+}
+
+// Oldest first; `cursor` is the next_cursor of the previous page
+#[ic_cdk::query]
+pub fn get_trades_by_buyer(buyer: Principal, cursor: Option<u64>, limit: Option<u32>) -> Result<TradePage, FactoryError> {
+    let size = page_size(limit)?;
+    STATE.with(|state| {
+        let state = state.borrow();
+        let ids = by_principal(&state.trades_by_buyer, buyer, cursor, size);
+        Ok(to_page(&state, ids.into_iter(), size))
+    })
+}
+
+#[ic_cdk::query]
+pub fn get_trades_by_token(token: Principal, cursor: Option<u64>, limit: Option<u32>) -> Result<TradePage, FactoryError> {
+    let size = page_size(limit)?;
+    STATE.with(|state| {
+        let state = state.borrow();
+        let ids = by_principal(&state.trades_by_token, token, cursor, size);
+        Ok(to_page(&state, ids.into_iter(), size))
+    })
+}
+
+// Trades with from <= timestamp < to, oldest first
+#[ic_cdk::query]
+pub fn get_trades_by_time(from: u64, to: u64, cursor: Option<u64>, limit: Option<u32>) -> Result<TradePage, FactoryError> {
+    let size = page_size(limit)?;
+    if from >= to {
+        return Err(FactoryError::InvalidArgument("The time range is empty".to_string()));
+    }
+
+    STATE.with(|state| {
+        let state = state.borrow();
+        let start = match cursor {
+            Some(id) => {
+                let trade = state.trades.get(&id)
+                    .ok_or_else(|| FactoryError::InvalidArgument(format!("Unknown cursor {}", id)))?;
+                (trade.timestamp.max(from), id)
+            }
+            None => (from, 0),
+        };
+        let ids = state.trades_by_time
+            .range((Bound::Included(start), Bound::Excluded((to, 0))))
+            .map(|((_, id), _)| id);
+        Ok(to_page(&state, ids, size))
+    })
+}
+
+#[ic_cdk::query]
+pub fn get_trade(trade_id: u64) -> Result<Trade, FactoryError> {
+    STATE.with(|state| state.borrow().trades.get(&trade_id))
+        .ok_or(FactoryError::TradeNotFound(trade_id))
+}
@@ -1,7 +1,10 @@
 use candid::{CandidType, Principal, Nat};
 use serde::{Deserialize, Serialize};
-use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
 use icrc_ledger_types::icrc::generic_value::Value;
+use icrc_ledger_types::icrc1::transfer::{Memo, TransferError};
+use icrc_ledger_types::icrc2::transfer_from::TransferFromError;
+use std::fmt;
 
 
 #[derive(CandidType, Serialize, Deserialize, Clone)]
@@ -13,8 +16,27 @@ pub struct TokenMetadata {
     pub owner: Principal,
     pub logo: Option<String>,
     pub created: u64,
+    // Ledger WASM version the token canister was installed with
+    pub wasm_version: Option<String>,
+    // Tokens created before pricing models existed sell at the fixed token_price
+    pub pricing: Option<PricingModel>,
+    // Lowercase catalog tags, see catalog::normalize_tags
+    pub tags: Option<Vec<String>>,
+    // ICRC index canister of the ledger, if one was installed with it
+    pub index_canister_id: Option<Principal>,
+    pub index_wasm_version: Option<String>,
 }
 
+impl TokenMetadata {
+    // token_price is in whole platform tokens, pricing models in platform base units
+    pub fn pricing_model(&self, platform_decimals: u8) -> PricingModel {
+        self.pricing.clone().unwrap_or_else(|| PricingModel::Fixed {
+            price: Nat::from(self.token_price) * crate::pricing::unit(platform_decimals),
+        })
+    }
+}
+
+// One entry per user as stored before per-request records, only read to migrate it
 #[derive(CandidType,Serialize, Deserialize, Clone)]
 pub struct FaucetTokenRequest{
     pub current_token_request: u32,
@@ -30,16 +52,770 @@ pub struct CreateTokenArgs {
     pub decimals: u8,
     pub token_price: u8,
     pub logo: Option<String>,
+    pub pricing: Option<PricingModel>,
+    pub tags: Option<Vec<String>>,
+}
+
+// How the price of a talent token moves with its circulating supply. Prices are in
+// platform base units per whole talent token.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum PricingModel {
+    Fixed { price: Nat },
+    // price = base_price + slope * supply
+    Linear { base_price: Nat, slope: Nat },
+    // price = base_price * (1 + growth_bps / 10_000) ^ supply
+    Exponential { base_price: Nat, growth_bps: u32 },
+}
+
+
+
+// Factory settings that must survive upgrades, kept in a StableCell
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct FactoryConfig {
+    pub admin: Principal,
+    pub token_canister_id: Principal,
+    pub is_admin_registered: bool,
+    // Registered WASM version used by create_talent_token_canister
+    pub active_wasm_version: Option<String>,
+    // Share of every purchase, in basis points, that funds the token's redemption reserve
+    pub reserve_share_bps: Option<u16>,
+    // Admin handover proposed by the current admin and not yet accepted
+    pub pending_admin: Option<PendingAdminTransfer>,
+    // Auto-approval pauses while the faucet treasury holds less than this
+    pub faucet_low_balance_threshold: Option<Nat>,
+    // Talent backend holding user profiles, consulted before a faucet request is accepted
+    pub backend_canister_id: Option<Principal>,
+    // Registered index-ng WASM version installed next to every new talent ledger; none when unset
+    pub active_index_wasm_version: Option<String>,
+}
+
+impl Default for FactoryConfig {
+    fn default() -> Self {
+        Self {
+            admin: Principal::management_canister(),
+            token_canister_id: Principal::anonymous(),
+            is_admin_registered: false,
+            active_wasm_version: None,
+            reserve_share_bps: None,
+            pending_admin: None,
+            faucet_low_balance_threshold: None,
+            backend_canister_id: None,
+            active_index_wasm_version: None,
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct FactoryInitArgs {
+    pub admin: Principal,
+    // Platform ledger used for fees and purchases
+    pub token_canister_id: Option<Principal>,
+    pub backend_canister_id: Option<Principal>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct PendingAdminTransfer {
+    pub candidate: Principal,
+    pub proposed_by: Principal,
+    pub proposed_at: u64,
+    pub expires_at: Option<u64>,
+}
+
+// Version name used for a WASM that was uploaded before the registry existed
+pub const LEGACY_WASM_VERSION: &str = "legacy";
+
+// What a stored module is installed as, so a ledger WASM never ends up on an index canister or the reverse
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WasmKind {
+    Ledger,
+    Index,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct WasmVersion {
+    pub version: String,
+    pub sha256: String,
+    pub size: u64,
+    pub uploaded_at: u64,
+    pub uploaded_by: Principal,
+    // None for versions registered before kinds were recorded, which are all ledgers
+    pub kind: Option<WasmKind>,
+}
+
+impl WasmVersion {
+    pub fn kind(&self) -> WasmKind {
+        self.kind.unwrap_or(WasmKind::Ledger)
+    }
+}
+
+// An upload in progress; the staged bytes live in the WASM module map under the same version
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct WasmUpload {
+    pub version: String,
+    pub uploader: Principal,
+    pub started_at: u64,
+    pub chunk_count: u32,
+    pub size: u64,
+    pub kind: Option<WasmKind>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub enum UpgradeTargets {
+    All,
+    Canisters(Vec<Principal>),
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct UpgradeLedgersArgs {
+    pub wasm_version: String,
+    // Index WASM for the ledgers' index canisters, upgraded right after their ledger.
+    // Defaults to the active index WASM version.
+    pub index_wasm_version: Option<String>,
+    pub targets: UpgradeTargets,
+    pub batch_size: Option<u32>,
+    pub batch_interval_seconds: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq)]
+pub enum LedgerUpgradeStatus {
+    Pending,
+    Upgraded { at: u64 },
+    Failed { at: u64, error: String },
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq)]
+pub enum UpgradeRunStatus {
+    Running,
+    Paused,
+    Completed,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct LedgerUpgradeRun {
+    pub id: u64,
+    pub wasm_version: String,
+    pub index_wasm_version: Option<String>,
+    pub started_by: Principal,
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    pub batch_size: u32,
+    pub batch_interval_seconds: u64,
+    pub status: UpgradeRunStatus,
+    pub canisters: Vec<(Principal, LedgerUpgradeStatus)>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct LedgerUpgradeReport {
+    pub id: u64,
+    pub wasm_version: String,
+    pub status: UpgradeRunStatus,
+    pub total: u64,
+    pub upgraded: u64,
+    pub pending: u64,
+    pub failures: Vec<(Principal, String)>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum CreationJobStatus {
+    Pending,
+    Charged,
+    CanisterCreated,
+    CodeInstalled,
+    // Only jobs with an index WASM version go through these two
+    IndexCreated,
+    IndexInstalled,
+    Registered,
+    Completed,
+    RolledBack,
+}
+
+// A talent token creation tracked step by step so it can be resumed or rolled back
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct CreationJob {
+    pub id: u64,
+    pub creator: Principal,
+    pub args: CreateTokenArgs,
+    pub fee: Nat,
+    pub wasm_version: String,
+    pub status: CreationJobStatus,
+    pub payment_block: Option<Nat>,
+    pub canister_id: Option<Principal>,
+    // Block of the fee release to the admin, or of the refund to the creator
+    pub settlement_block: Option<Nat>,
+    pub last_error: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+    // Subaccount of the creator the fee is charged from and refunded to
+    pub fee_subaccount: Option<Subaccount>,
+    // Index WASM version to install next to the ledger, taken when the job is created
+    pub index_wasm_version: Option<String>,
+    pub index_canister_id: Option<Principal>,
+}
+
+impl CreationJob {
+    pub fn fee_account(&self) -> Account {
+        Account { owner: self.creator, subaccount: self.fee_subaccount }
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum PurchaseStatus {
+    Pending,
+    Escrowed,
+    Minted,
+    SellerPaid,
+    Completed,
+    Refunded,
+    Failed,
+}
+
+// A talent token purchase; payment sits in escrow until the tokens are minted
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct Purchase {
+    pub id: u64,
+    pub buyer: Principal,
+    pub token: Principal,
+    pub seller: Principal,
+    pub quantity: Nat,
+    pub total_cost: Nat,
+    pub status: PurchaseStatus,
+    pub payment_block: Option<Nat>,
+    pub mint_block: Option<Nat>,
+    // Block of the payment release to the seller, or of the refund to the buyer
+    pub settlement_block: Option<Nat>,
+    pub last_error: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+    // Part of total_cost that goes to the token's reserve instead of the seller
+    pub reserve_amount: Option<Nat>,
+    pub reserve_block: Option<Nat>,
+    // Subaccount of the buyer that pays and gets refunds
+    pub payment_subaccount: Option<Subaccount>,
+    // Where the minted tokens go; the buyer's default account if not set
+    pub receiver: Option<Account>,
+}
+
+impl Purchase {
+    pub fn payer_account(&self) -> Account {
+        Account { owner: self.buyer, subaccount: self.payment_subaccount }
+    }
+
+    pub fn receiver_account(&self) -> Account {
+        self.receiver.unwrap_or_else(|| Account::from(self.buyer))
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum RedemptionStatus {
+    Pending,
+    Burned,
+    Paid,
+    Failed,
+}
+
+// A sale of talent tokens back to the factory, paid out of the token's reserve
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct Redemption {
+    pub id: u64,
+    pub seller: Principal,
+    pub token: Principal,
+    pub quantity: Nat,
+    pub payout: Nat,
+    pub status: RedemptionStatus,
+    pub burn_block: Option<Nat>,
+    pub payout_block: Option<Nat>,
+    pub last_error: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+    // Subaccount of the seller the tokens are burned from and the payout goes to
+    pub subaccount: Option<Subaccount>,
+}
+
+impl Redemption {
+    pub fn seller_account(&self) -> Account {
+        Account { owner: self.seller, subaccount: self.subaccount }
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct PaymentArgs {
+    pub to: Principal,
+    pub amount: Nat,
+    // Passed on to the ledger transfer, at most 32 bytes
+    pub memo: Option<Memo>,
+    // Free-form tag saying what the payment is for, e.g. "tip" or "invoice"
+    pub purpose: String,
+    // Chosen by the payer; calling pay again with the same key returns the first payment
+    pub idempotency_key: String,
+    pub from_subaccount: Option<Subaccount>,
+    pub to_subaccount: Option<Subaccount>,
+}
+
+// A client idempotency key the factory already acted on, kept for the ledger's dedup window
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct ProcessedKey {
+    pub operation: String,
+    // The purchase, creation job or redemption the key started
+    pub record_id: Option<u64>,
+    pub first_seen: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum PaymentStatus {
+    Pending,
+    Completed,
+    Failed,
+}
+
+// A transfer the factory made from the payer's approved allowance through pay
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct Payment {
+    pub id: u64,
+    pub payer: Principal,
+    pub to: Principal,
+    pub amount: Nat,
+    pub memo: Option<Memo>,
+    pub purpose: String,
+    pub idempotency_key: String,
+    pub status: PaymentStatus,
+    pub block: Option<Nat>,
+    pub last_error: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub from_subaccount: Option<Subaccount>,
+    pub to_subaccount: Option<Subaccount>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    // Holds every other role and manages roles, WASM versions and platform settings
+    SuperAdmin,
+    FaucetOperator,
+    Moderator,
+    Treasurer,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RoleSet(pub Vec<Role>);
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum RoleChange {
+    Granted,
+    Revoked,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RoleAuditEntry {
+    pub id: u64,
+    pub actor: Principal,
+    pub principal: Principal,
+    pub role: Role,
+    pub change: RoleChange,
+    pub at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum FaucetStatus {
+    Pending,
+    // Approved by a reviewer, payment not made or not confirmed yet; approving it again retries the payment
+    Approved,
+    Rejected,
+    // The ledger rejected the payment; the request can be approved again
+    Failed,
+    Paid,
 }
 
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct FaucetRequest {
+    pub id: u64,
+    pub requester: Principal,
+    pub amount: Nat,
+    pub requested_at: u64,
+    pub status: FaucetStatus,
+    pub reviewed_by: Option<Principal>,
+    pub reviewed_at: Option<u64>,
+    pub payment_block: Option<Nat>,
+    pub last_error: Option<String>,
+    // Account the tokens are paid to; the requester's default account if not set
+    pub to: Option<Account>,
+    // When the first payment attempt started; retries reuse it so the ledger deduplicates them
+    pub payment_started_at: Option<u64>,
+}
+
+impl FaucetRequest {
+    pub fn recipient(&self) -> Account {
+        self.to.unwrap_or_else(|| Account::from(self.requester))
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct FaucetPolicy {
+    pub max_per_request: Option<Nat>,
+    // Rolling 24 hours
+    pub max_per_day: Option<Nat>,
+    pub max_lifetime: Option<Nat>,
+    // Minimum time between two requests of the same principal
+    pub cooldown_seconds: u64,
+    // Pending requests up to this amount are approved and paid by the factory without review
+    pub auto_approve_up_to: Option<Nat>,
+    pub auto_approval_interval_seconds: u64,
+    // Who may ask the faucet at all; policies saved before these rules existed use the defaults
+    pub eligibility: Option<FaucetEligibility>,
+}
+
+impl FaucetPolicy {
+    pub fn eligibility(&self) -> FaucetEligibility {
+        self.eligibility.clone().unwrap_or_default()
+    }
+}
+
+// All checks are off by default; profile checks can only be turned on once a backend canister is set
+#[derive(CandidType, Serialize, Deserialize, Clone, Default)]
+pub struct FaucetEligibility {
+    // The requester needs a UserProfile in the talent backend; needs a backend canister
+    pub require_profile: bool,
+    // Minimum age of that profile; needs require_profile
+    pub min_profile_age_seconds: Option<u64>,
+    // The requester needs to be marked verified by a Moderator
+    pub require_verified: bool,
+    // Leading zero bits required of the proof-of-work hash, see get_faucet_pow_challenge
+    pub pow_difficulty_bits: Option<u8>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct VerifiedUser {
+    pub verified_by: Principal,
+    pub verified_at: u64,
+}
+
+// The fields the faucet reads from the backend's UserProfile; candid skips the rest
+#[derive(CandidType, Deserialize, Clone)]
+pub struct BackendUserProfile {
+    pub id: Principal,
+    pub created_at: Option<u64>,
+}
+
+// A request passes the proof of work with a nonce for which sha256(prefix ++ nonce as
+// 8 big-endian bytes) starts with difficulty_bits zero bits
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct FaucetPowChallenge {
+    pub prefix: Vec<u8>,
+    pub difficulty_bits: u8,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum FaucetIneligibility {
+    NoProfile,
+    ProfileTooNew { eligible_at: u64 },
+    NotVerified,
+    MissingProofOfWork,
+    InvalidProofOfWork,
+}
+
+impl Default for FaucetPolicy {
+    fn default() -> Self {
+        Self {
+            max_per_request: Some(Nat::from(1_000u32)),
+            max_per_day: None,
+            max_lifetime: None,
+            cooldown_seconds: 0,
+            auto_approve_up_to: None,
+            auto_approval_interval_seconds: 60,
+            eligibility: None,
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Default)]
+pub struct FaucetRequestFilter {
+    pub max_amount: Option<Nat>,
+    pub requested_after: Option<u64>,
+    pub requested_before: Option<u64>,
+}
+
+impl FaucetRequestFilter {
+    pub fn matches(&self, request: &FaucetRequest) -> bool {
+        self.max_amount.as_ref().is_none_or(|max| request.amount <= *max)
+            && self.requested_after.is_none_or(|after| request.requested_at >= after)
+            && self.requested_before.is_none_or(|before| request.requested_at < before)
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub enum FaucetBatchSelection {
+    Requests(Vec<u64>),
+    // The pending requests of these principals
+    Requesters(Vec<Principal>),
+    // Pending requests matching the filter, oldest first
+    Pending(FaucetRequestFilter),
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct FaucetBatchOutcome {
+    pub request_id: u64,
+    pub result: Result<FaucetRequest, FactoryError>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct FaucetTreasury {
+    pub account: Account,
+    pub balance: Nat,
+    pub low_balance_threshold: Option<Nat>,
+    pub is_low: bool,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct TokenBalance {
+    pub token: Principal,
+    pub subaccount: Option<Subaccount>,
+    // Last known balance; from the cache when the ledger could not be read this time
+    pub balance: Option<Nat>,
+    // When the balance was read from the ledger
+    pub fetched_at: Option<u64>,
+    pub error: Option<String>,
+}
+
+// One purchase of talent tokens, appended to the trade log once the tokens are minted
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct Trade {
+    pub id: u64,
+    pub buyer: Principal,
+    pub token: Principal,
+    // Talent base units. The amounts are None for trades migrated from the old purchase
+    // history when no purchase record was left to fill them in.
+    pub quantity: Option<Nat>,
+    // Platform base units per whole talent token
+    pub unit_price: Option<Nat>,
+    pub total_paid: Option<Nat>,
+    pub payment_block: Option<Nat>,
+    pub mint_block: Option<Nat>,
+    pub timestamp: u64,
+    pub purchase_id: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct TradePage {
+    pub trades: Vec<Trade>,
+    // Pass as the cursor to get the next page; None on the last page
+    pub next_cursor: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum CandleInterval {
+    Hour,
+    Day,
+    Week,
+}
+
+// Prices are platform base units per whole talent token. Buckets without trades have no candle.
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct Candle {
+    // Start of the bucket in nanoseconds
+    pub start: u64,
+    pub open: Nat,
+    pub high: Nat,
+    pub low: Nat,
+    pub close: Nat,
+    // Talent base units bought and sold
+    pub volume: Nat,
+    // Platform base units paid and paid out
+    pub quote_volume: Nat,
+    pub trades: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct MarketSummary {
+    pub token: Principal,
+    pub last_price: Option<Nat>,
+    // Last price before the 24h window, or the first price in it
+    pub price_24h_ago: Option<Nat>,
+    // Change of last_price against price_24h_ago in basis points
+    pub change_24h_bps: Option<i64>,
+    pub high_24h: Option<Nat>,
+    pub low_24h: Option<Nat>,
+    pub volume_24h: Nat,
+    pub quote_volume_24h: Nat,
+    pub trades_24h: u64,
+}
+
+// Catalog figures of a token that change with trading
+#[derive(CandidType, Serialize, Deserialize, Clone, Default)]
+pub struct TokenStats {
+    // Spot price in platform base units per whole token
+    pub price: Nat,
+    // Platform base units paid and paid out over the token's lifetime
+    pub volume: Nat,
+    // Accounts with a positive balance by the factory's own purchases and sales. Ledger
+    // transfers between users and buyers from before purchase records are not seen.
+    pub holders: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum TokenSort {
+    Created,
+    Name,
+    Price,
+    Volume,
+    Holders,
+}
 
+#[derive(CandidType, Serialize, Deserialize, Clone, Default)]
+pub struct ListTokensArgs {
+    // next_cursor of the previous page, only valid with the same sort
+    pub cursor: Option<Vec<u8>>,
+    pub limit: Option<u32>,
+    // Defaults to Created
+    pub sort: Option<TokenSort>,
+    pub descending: Option<bool>,
+    pub owner: Option<Principal>,
+    pub symbol_prefix: Option<String>,
+    // Tokens carrying all of these tags
+    pub tags: Option<Vec<String>>,
+    // Inclusive bounds on the spot price
+    pub min_price: Option<Nat>,
+    pub max_price: Option<Nat>,
+}
 
-#[derive(CandidType, Serialize, Deserialize)]
-pub enum Error {
-    NotAuthorized,
-    RequestNotFound,
-    TransferFailed(String),
-    // ... other variants ...
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct TokenListing {
+    pub token: Principal,
+    pub metadata: TokenMetadata,
+    pub stats: TokenStats,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct TokenListPage {
+    pub tokens: Vec<TokenListing>,
+    // Set while there is more to look at, even if this page came back short
+    pub next_cursor: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub enum QuoteKind {
+    Creation,
+    // `quantity` in talent base units
+    Purchase { token: Principal, quantity: Nat },
+    FaucetTopUp { amount: Nat },
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct QuoteArgs {
+    pub kind: QuoteKind,
+    // Subaccount of the caller that will pay
+    pub from_subaccount: Option<Subaccount>,
+}
+
+// What an operation will take from the caller's account on the platform ledger
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct Quote {
+    pub ledger: Principal,
+    // Account to approve with icrc2_approve
+    pub spender: Account,
+    pub amount: Nat,
+    // Ledger fee the transfer_from charges on top of the amount
+    pub fee: Nat,
+    // Allowance the spender needs: amount plus fee
+    pub approve_amount: Nat,
+    pub current_allowance: Nat,
+    pub allowance_expires_at: Option<u64>,
+    pub balance: Nat,
+    pub allowance_sufficient: bool,
+    pub balance_sufficient: bool,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum FaucetLimit {
+    PerRequest,
+    PerDay,
+    Lifetime,
+}
+
+// Every factory endpoint fails with one of these, so clients can match on the
+// variant instead of parsing messages
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum FactoryError {
+    NotAuthorized { caller: Principal },
+    MissingRole { caller: Principal, role: Role },
+    AnonymousCaller,
+    NoPendingAdminTransfer,
+    AdminTransferExpired { expired_at: u64 },
+    InvalidArgument(String),
+    InvalidState(String),
+    TokenNotFound(Principal),
+    AlreadyHasToken(Principal),
+    FaucetRequestNotFound(u64),
+    // `available` is what the caller could still request under the limit
+    FaucetLimitExceeded { limit: FaucetLimit, available: Nat },
+    FaucetCooldown { retry_at: u64 },
+    FaucetIneligible(FaucetIneligibility),
+    CreationJobNotFound(u64),
+    PurchaseNotFound(u64),
+    RedemptionNotFound(u64),
+    PaymentNotFound(u64),
+    TradeNotFound(u64),
+    UpgradeRunNotFound(u64),
+    WasmVersionNotFound(String),
+    WasmVersionExists(String),
+    WasmUploadNotFound(String),
+    WasmKindMismatch { version: String, expected: WasmKind, found: WasmKind },
+    NoActiveWasmVersion,
+    InProgress { operation: String, id: u64 },
+    InsufficientAllowance { required: Nat, available: Nat },
+    InsufficientFunds { required: Nat, available: Nat },
+    InsufficientReserve { required: Nat, available: Nat },
+    LedgerRejected(TransferFromError),
+    TransferRejected(TransferError),
+    CallFailed { canister: Principal, method: String, reason: String },
+    // The operation failed and everything it had done was undone
+    RolledBack(Box<FactoryError>),
+}
+
+impl fmt::Display for FactoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotAuthorized { caller } => write!(f, "Not authorized, caller: {}", caller),
+            Self::MissingRole { caller, role } => write!(f, "Caller {} does not have the {:?} role", caller, role),
+            Self::AnonymousCaller => write!(f, "Anonymous calls not allowed"),
+            Self::NoPendingAdminTransfer => write!(f, "No admin transfer is pending"),
+            Self::AdminTransferExpired { expired_at } => write!(f, "Admin transfer expired at {}", expired_at),
+            Self::InvalidArgument(message) | Self::InvalidState(message) => write!(f, "{}", message),
+            Self::TokenNotFound(token) => write!(f, "Token {} not found", token),
+            Self::AlreadyHasToken(token) => write!(f, "Caller already created token {}", token),
+            Self::FaucetRequestNotFound(id) => write!(f, "Faucet request {} not found", id),
+            Self::FaucetLimitExceeded { limit, available } => {
+                write!(f, "Faucet {:?} limit exceeded, {} tokens available", limit, available)
+            }
+            Self::FaucetCooldown { retry_at } => write!(f, "Faucet cooldown active until {}", retry_at),
+            Self::FaucetIneligible(reason) => write!(f, "Not eligible for the faucet: {:?}", reason),
+            Self::CreationJobNotFound(id) => write!(f, "Creation job {} not found", id),
+            Self::PurchaseNotFound(id) => write!(f, "Purchase {} not found", id),
+            Self::RedemptionNotFound(id) => write!(f, "Redemption {} not found", id),
+            Self::PaymentNotFound(id) => write!(f, "Payment {} not found", id),
+            Self::TradeNotFound(id) => write!(f, "Trade {} not found", id),
+            Self::UpgradeRunNotFound(id) => write!(f, "Ledger upgrade run {} not found", id),
+            Self::WasmVersionNotFound(version) => write!(f, "WASM version {} not found", version),
+            Self::WasmVersionExists(version) => write!(f, "WASM version {} is already registered", version),
+            Self::WasmUploadNotFound(version) => write!(f, "No upload in progress for WASM version {}", version),
+            Self::WasmKindMismatch { version, expected, found } => {
+                write!(f, "WASM version {} is a {:?} module, expected {:?}", version, found, expected)
+            }
+            Self::NoActiveWasmVersion => write!(f, "No active WASM version set"),
+            Self::InProgress { operation, id } => write!(f, "{} {} is already in progress", operation, id),
+            Self::InsufficientAllowance { required, available } => {
+                write!(f, "Insufficient allowance: {} required, {} approved", required, available)
+            }
+            Self::InsufficientFunds { required, available } => {
+                write!(f, "Insufficient funds: {} required, {} available", required, available)
+            }
+            Self::InsufficientReserve { required, available } => {
+                write!(f, "Reserve too low: {} required, {} available", required, available)
+            }
+            Self::LedgerRejected(e) => write!(f, "Ledger rejected transfer_from: {}", e),
+            Self::TransferRejected(e) => write!(f, "Ledger rejected transfer: {}", e),
+            Self::CallFailed { canister, method, reason } => write!(f, "Call to {}.{} failed: {}", canister, method, reason),
+            Self::RolledBack(e) => write!(f, "{}; the operation was rolled back", e),
+        }
+    }
 }
 
 #[derive(CandidType, Serialize, Deserialize, Debug)]
@@ -91,10 +867,30 @@ pub struct ArchiveOptions {
 pub struct UpgradeArgs {}
 
 #[derive(Debug, Serialize, Deserialize, CandidType)]
+#[allow(clippy::large_enum_variant)]
 pub enum LedgerArg {
     Init(InitArgs),
     Upgrade(Option<UpgradeArgs>),
 }
 
+// Init and upgrade arguments of the ICRC index-ng canister
+#[derive(Debug, Serialize, Deserialize, CandidType)]
+pub struct IndexInitArgs {
+    pub ledger_id: Principal,
+    pub retrieve_blocks_from_ledger_interval_seconds: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, CandidType)]
+pub struct IndexUpgradeArgs {
+    pub ledger_id: Option<Principal>,
+    pub retrieve_blocks_from_ledger_interval_seconds: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, CandidType)]
+pub enum IndexArg {
+    Init(IndexInitArgs),
+    Upgrade(IndexUpgradeArgs),
+}
+
 #[derive(Debug, Clone)]
 pub struct PrincipalVec(pub Vec<Principal>);
@@ -0,0 +1,193 @@
+use crate::state_handler::{ensure_role, State, STATE};
+use crate::types::*;
+use ic_cdk::api::caller;
+use sha2::{Digest, Sha256};
+
+// Upper bound for a staged module, well above the size of the ICRC ledger WASM
+const MAX_WASM_SIZE: u64 = 10 * 1024 * 1024;
+
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+// `version` is registered and was uploaded as a module of the expected kind
+pub fn ensure_wasm_kind(state: &State, version: &str, expected: WasmKind) -> Result<(), FactoryError> {
+    let found = state.wasm_versions.get(&version.to_string())
+        .ok_or_else(|| FactoryError::WasmVersionNotFound(version.to_string()))?
+        .kind();
+    if found != expected {
+        return Err(FactoryError::WasmKindMismatch { version: version.to_string(), expected, found });
+    }
+    Ok(())
+}
+
+// Stage a new module; `kind` defaults to Ledger
+#[ic_cdk::update]
+pub fn begin_wasm_upload(version: String, kind: Option<WasmKind>) -> Result<String, FactoryError> {
+    let uploader = caller();
+    ensure_role(uploader, Role::SuperAdmin)?;
+
+    if version.trim().is_empty() {
+        return Err(FactoryError::InvalidArgument("Version name cannot be empty".to_string()));
+    }
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.wasm_versions.contains_key(&version) {
+            return Err(FactoryError::WasmVersionExists(version));
+        }
+
+        // Starting again discards whatever was staged for this version
+        state.wasm_modules.insert(version.clone(), Vec::new());
+        state.wasm_uploads.insert(version.clone(), WasmUpload {
+            version: version.clone(),
+            uploader,
+            started_at: ic_cdk::api::time(),
+            chunk_count: 0,
+            size: 0,
+            kind: Some(kind.unwrap_or(WasmKind::Ledger)),
+        });
+
+        Ok(format!("Upload started for WASM version {}", version))
+    })
+}
+
+#[ic_cdk::update]
+pub fn append_wasm_chunk(version: String, chunk: Vec<u8>) -> Result<u64, FactoryError> {
+    ensure_role(caller(), Role::SuperAdmin)?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let mut upload = state.wasm_uploads.get(&version)
+            .ok_or_else(|| FactoryError::WasmUploadNotFound(version.clone()))?;
+
+        let new_size = upload.size + chunk.len() as u64;
+        if new_size > MAX_WASM_SIZE {
+            return Err(FactoryError::InvalidArgument(format!("WASM module exceeds the maximum size of {} bytes", MAX_WASM_SIZE)));
+        }
+
+        let mut module = state.wasm_modules.get(&version).unwrap_or_default();
+        module.extend_from_slice(&chunk);
+        state.wasm_modules.insert(version.clone(), module);
+
+        upload.chunk_count += 1;
+        upload.size = new_size;
+        state.wasm_uploads.insert(version, upload);
+
+        Ok(new_size)
+    })
+}
+
+#[ic_cdk::update]
+pub fn commit_wasm_upload(version: String, expected_sha256: String) -> Result<WasmVersion, FactoryError> {
+    ensure_role(caller(), Role::SuperAdmin)?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let upload = state.wasm_uploads.get(&version)
+            .ok_or_else(|| FactoryError::WasmUploadNotFound(version.clone()))?;
+        let module = state.wasm_modules.get(&version).unwrap_or_default();
+
+        if module.is_empty() {
+            return Err(FactoryError::InvalidState("Cannot commit an empty WASM module".to_string()));
+        }
+
+        // Keep the staged bytes on a mismatch so the upload can be inspected or aborted
+        let sha256 = sha256_hex(&module);
+        if sha256 != expected_sha256.trim().to_lowercase() {
+            return Err(FactoryError::InvalidArgument(format!("SHA-256 mismatch: expected {}, uploaded module has {}", expected_sha256, sha256)));
+        }
+
+        let wasm_version = WasmVersion {
+            version: version.clone(),
+            sha256,
+            size: module.len() as u64,
+            uploaded_at: ic_cdk::api::time(),
+            uploaded_by: upload.uploader,
+            kind: upload.kind,
+        };
+        state.wasm_versions.insert(version.clone(), wasm_version.clone());
+        state.wasm_uploads.remove(&version);
+
+        Ok(wasm_version)
+    })
+}
+
+#[ic_cdk::update]
+pub fn abort_wasm_upload(version: String) -> Result<String, FactoryError> {
+    ensure_role(caller(), Role::SuperAdmin)?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.wasm_uploads.remove(&version).is_none() {
+            return Err(FactoryError::WasmUploadNotFound(version));
+        }
+        state.wasm_modules.remove(&version);
+        Ok(format!("Upload aborted for WASM version {}", version))
+    })
+}
+
+#[ic_cdk::update]
+pub fn set_active_wasm_version(version: String) -> Result<String, FactoryError> {
+    ensure_role(caller(), Role::SuperAdmin)?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        ensure_wasm_kind(&state, &version, WasmKind::Ledger)?;
+        state.update_config(|config| config.active_wasm_version = Some(version.clone()));
+        Ok(format!("Active WASM version set to {}", version))
+    })
+}
+
+// Pick the index-ng WASM installed next to new talent ledgers, or stop creating index canisters with None
+#[ic_cdk::update]
+pub fn set_active_index_wasm_version(version: Option<String>) -> Result<String, FactoryError> {
+    ensure_role(caller(), Role::SuperAdmin)?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if let Some(version) = &version {
+            ensure_wasm_kind(&state, version, WasmKind::Index)?;
+        }
+        state.update_config(|config| config.active_index_wasm_version = version.clone());
+        Ok(match version {
+            Some(version) => format!("Active index WASM version set to {}", version),
+            None => "Index canisters are no longer created".to_string(),
+        })
+    })
+}
+
+#[ic_cdk::query]
+pub fn get_active_index_wasm_version() -> Result<Option<WasmVersion>, FactoryError> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        Ok(state.config.get().active_index_wasm_version.as_ref()
+            .and_then(|version| state.wasm_versions.get(version)))
+    })
+}
+
+#[ic_cdk::query]
+pub fn get_wasm_versions() -> Result<Vec<WasmVersion>, FactoryError> {
+    STATE.with(|state| {
+        Ok(state.borrow().wasm_versions.values().collect())
+    })
+}
+
+#[ic_cdk::query]
+pub fn get_active_wasm_version() -> Result<WasmVersion, FactoryError> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        state.config.get().active_wasm_version.as_ref()
+            .and_then(|version| state.wasm_versions.get(version))
+            .ok_or(FactoryError::NoActiveWasmVersion)
+    })
+}
+
+#[ic_cdk::query]
+pub fn get_wasm_uploads() -> Result<Vec<WasmUpload>, FactoryError> {
+    ensure_role(caller(), Role::SuperAdmin)?;
+
+    STATE.with(|state| {
+        Ok(state.borrow().wasm_uploads.values().collect())
+    })
+}